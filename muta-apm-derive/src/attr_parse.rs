@@ -1,152 +1,608 @@
 use std::collections::HashMap;
 
-use proc_macro2::TokenStream;
+use proc_macro2::{Span, TokenStream};
 use quote::quote;
-use syn::{parse_str, Expr, Lit, Meta, NestedMeta};
+use syn::ext::IdentExt;
+use syn::parse::ParseStream;
+use syn::{parenthesized, parse_str, token, Expr, ExprLit, Ident, Lit, LitStr, Token};
 
 static KIND: &str = "kind";
 static TRACING_NAME: &str = "name";
 static TRACING_TAGS: &str = "tags";
 static TRACING_LOGS: &str = "logs";
+static TRACING_CTX: &str = "ctx";
+static TRACING_FIELDS: &str = "fields";
+static TRACING_SKIP: &str = "skip";
+static FIELDS_MAX_LEN: &str = "fields_max_len";
+static FIELDS_ALL: &str = "all";
+static TRACING_RET: &str = "ret";
+static TRACING_ERR: &str = "err";
+static TRACING_NO_ERR: &str = "no_err";
+static SPAN_KIND: &str = "span_kind";
+static TRACING_ROOT: &str = "root";
+static TRACING_FOLLOWS_FROM: &str = "follows_from";
+static TRACING_IF: &str = "if";
+static SAMPLE_RATE: &str = "sample_rate";
+static SLOW_THRESHOLD_MS: &str = "slow_threshold_ms";
+
+static SPAN_KINDS: &[&str] = &["client", "server", "producer", "consumer", "internal"];
+
+static TRACING_OPTIONS: &[&str] = &[
+    "kind",
+    "name",
+    "tags",
+    "logs",
+    "ctx",
+    "fields",
+    "skip",
+    "fields_max_len",
+    "ret",
+    "err",
+    "no_err",
+    "span_kind",
+    "root",
+    "follows_from",
+    "if",
+    "sample_rate",
+    "slow_threshold_ms",
+];
+
+/// How a function argument recorded by `fields(..)` is converted to a tag.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FieldFormat {
+    // `ToTagValue` if implemented, `Display` otherwise.
+    Value,
+    Display,
+    Debug,
+}
+
+/// How the span references the span of the `creep::Context`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpanRef {
+    ChildOf,
+    FollowsFrom,
+    // Start a new trace, ignoring the parent span.
+    Root,
+}
+
+/// What is recorded when the function returns `Err`, the message and the
+/// kind by default, `err(..)` picks among `msg`, `kind`, `source`, `debug`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ErrRecord {
+    pub msg:    bool,
+    pub kind:   bool,
+    pub source: bool,
+    pub debug:  bool,
+}
+
+impl Default for ErrRecord {
+    fn default() -> Self {
+        ErrRecord {
+            msg:    true,
+            kind:   true,
+            source: false,
+            debug:  false,
+        }
+    }
+}
 
 pub struct TracingAttrs {
-    pub kind:         String,
-    pub tracing_name: Option<String>,
-    pub tracing_tags: HashMap<String, String>,
-    pub tracing_logs: HashMap<String, String>,
+    pub kind:              String,
+    pub tracing_name:      Option<LitStr>,
+    pub tracing_tags:      Vec<(String, Expr)>,
+    pub tracing_logs:      Vec<(String, Expr)>,
+    pub tracing_ctx:       Option<Expr>,
+    pub fields_all:        bool,
+    pub fields:            Vec<(Ident, FieldFormat)>,
+    pub skip:              Vec<Ident>,
+    pub fields_len:        Option<usize>,
+    pub ret_format:        Option<FieldFormat>,
+    // Overrides the detection of `Result` return types.
+    pub err:               Option<bool>,
+    pub err_record:        ErrRecord,
+    pub span_kind:         Option<String>,
+    pub span_ref:          SpanRef,
+    pub tracing_if:        Option<Expr>,
+    pub sample_rate:       Option<f64>,
+    pub slow_threshold_ms: Option<u64>,
 }
 
 impl Default for TracingAttrs {
     fn default() -> Self {
         TracingAttrs {
-            kind:         String::new(),
-            tracing_name: None,
-            tracing_tags: HashMap::new(),
-            tracing_logs: HashMap::new(),
+            kind:              String::new(),
+            tracing_name:      None,
+            tracing_tags:      Vec::new(),
+            tracing_logs:      Vec::new(),
+            tracing_ctx:       None,
+            fields_all:        false,
+            fields:            Vec::new(),
+            skip:              Vec::new(),
+            fields_len:        None,
+            ret_format:        None,
+            err:               None,
+            err_record:        ErrRecord::default(),
+            span_kind:         None,
+            span_ref:          SpanRef::ChildOf,
+            tracing_if:        None,
+            sample_rate:       None,
+            slow_threshold_ms: None,
         }
     }
 }
 
 impl TracingAttrs {
-    pub fn get_tracing_name(&self) -> Option<String> {
-        self.tracing_name.clone()
-    }
-
-    pub fn get_tag_map(&self) -> HashMap<String, String> {
-        let mut res = self.tracing_tags.clone();
-        res.insert("kind".to_string(), self.kind.clone());
+    pub fn get_tags(&self) -> Vec<(String, Expr)> {
+        let mut res = vec![(KIND.to_string(), str_expr(&self.kind))];
+        if let Some(span_kind) = self.span_kind.as_ref() {
+            res.push(("span.kind".to_string(), str_expr(span_kind)));
+        }
+        res.extend(self.tracing_tags.iter().cloned());
         res
     }
 
-    pub fn get_log_map(&self) -> HashMap<String, String> {
+    pub fn get_logs(&self) -> Vec<(String, Expr)> {
         self.tracing_logs.clone()
     }
 
+    pub fn get_tracing_ctx(&self) -> Option<Expr> {
+        self.tracing_ctx.clone()
+    }
+
+    /// Whether every argument not skipped is recorded, `skip(..)` alone
+    /// implies `fields(all)`.
+    pub fn is_fields_all(&self) -> bool {
+        self.fields_all || !self.skip.is_empty()
+    }
+
     fn set_kind(&mut self, kind: String) {
         self.kind = kind;
     }
 
-    fn set_tracing_name(&mut self, name: String) {
+    fn set_tracing_name(&mut self, name: LitStr) {
         self.tracing_name = Some(name);
     }
 
-    fn set_tracing_tags(&mut self, tags: HashMap<String, String>) {
+    fn set_tracing_tags(&mut self, tags: Vec<(String, Expr)>) {
         self.tracing_tags = tags;
     }
 
-    fn set_tracing_logs(&mut self, logs: HashMap<String, String>) {
+    fn set_tracing_logs(&mut self, logs: Vec<(String, Expr)>) {
         self.tracing_logs = logs;
     }
+
+    fn set_tracing_ctx(&mut self, ctx: Expr) {
+        self.tracing_ctx = Some(ctx);
+    }
+
+    fn set_fields(&mut self, all: bool, fields: Vec<(Ident, FieldFormat)>) {
+        self.fields_all = all;
+        self.fields = fields;
+    }
+
+    fn set_skip(&mut self, skip: Vec<Ident>) {
+        self.skip = skip;
+    }
+
+    fn set_fields_len(&mut self, len: usize) {
+        self.fields_len = Some(len);
+    }
+
+    fn set_ret_format(&mut self, format: FieldFormat) {
+        self.ret_format = Some(format);
+    }
+
+    fn set_err(&mut self, err: bool) {
+        self.err = Some(err);
+    }
+
+    fn set_err_record(&mut self, record: ErrRecord) {
+        self.err_record = record;
+    }
+
+    fn set_span_kind(&mut self, span_kind: String) {
+        self.span_kind = Some(span_kind);
+    }
+
+    fn set_tracing_if(&mut self, cond: Expr) {
+        self.tracing_if = Some(cond);
+    }
+
+    fn set_sample_rate(&mut self, rate: f64) {
+        self.sample_rate = Some(rate);
+    }
+
+    fn set_slow_threshold_ms(&mut self, threshold_ms: u64) {
+        self.slow_threshold_ms = Some(threshold_ms);
+    }
+
+    fn set_span_ref(&mut self, ident: &Ident, span_ref: SpanRef) -> syn::Result<()> {
+        if self.span_ref != SpanRef::ChildOf && self.span_ref != span_ref {
+            return Err(syn::Error::new(
+                ident.span(),
+                "`root` and `follows_from` can't be used together",
+            ));
+        }
+
+        self.span_ref = span_ref;
+        Ok(())
+    }
+}
+
+/// Arguments of `#[tracing_impl]`, the shared kind and the methods left
+/// uninstrumented.
+#[derive(Default)]
+pub struct ImplAttrs {
+    pub kind: Option<LitStr>,
+    pub skip: Vec<Ident>,
+}
+
+pub fn parse_impl_attrs(input: ParseStream) -> syn::Result<ImplAttrs> {
+    let mut attrs = ImplAttrs::default();
+
+    while !input.is_empty() {
+        let ident = input.call(Ident::parse_any)?;
+
+        if ident == KIND && input.peek(Token![=]) {
+            input.parse::<Token![=]>()?;
+            attrs.kind = Some(input.parse::<LitStr>()?);
+        } else if ident == TRACING_SKIP && input.peek(token::Paren) {
+            let content;
+            parenthesized!(content in input);
+            let skip = content.parse_terminated::<Ident, Token![,]>(Ident::parse_any)?;
+            attrs.skip.extend(skip);
+        } else {
+            return Err(syn::Error::new(
+                ident.span(),
+                format!(
+                    "unknown tracing_impl option `{}`, expected `{} = \"..\"` or `{}(..)`",
+                    ident, KIND, TRACING_SKIP
+                ),
+            ));
+        }
+
+        if input.is_empty() {
+            break;
+        }
+        input.parse::<Token![,]>()?;
+    }
+
+    Ok(attrs)
 }
 
-pub fn parse_attrs(input: Vec<NestedMeta>) -> TracingAttrs {
+/// Parse `#[tracing_span]` arguments, both the native form
+/// `tags(height = height, "span.kind" = "server")` and the legacy
+/// single-quoted json form `tags = "{'height': 'height'}"` are accepted.
+pub fn parse_attrs(input: ParseStream) -> syn::Result<TracingAttrs> {
     let mut attrs = TracingAttrs::default();
-    for attr in input.iter() {
-        match_attr(&mut attrs, attr);
+
+    while !input.is_empty() {
+        let ident = input.call(Ident::parse_any)?;
+
+        if input.peek(Token![=]) {
+            input.parse::<Token![=]>()?;
+            let lit = input.parse::<Lit>()?;
+            match_name_value(&mut attrs, &ident, &lit)?;
+        } else if input.peek(token::Paren) {
+            let content;
+            parenthesized!(content in input);
+            match_list(&mut attrs, &ident, &content)?;
+        } else if input.is_empty() || input.peek(Token![,]) {
+            match_flag(&mut attrs, &ident)?;
+        } else {
+            return Err(syn::Error::new(
+                ident.span(),
+                format!("expected `{} = ..` or `{}(..)`", ident, ident),
+            ));
+        }
+
+        if input.is_empty() {
+            break;
+        }
+        input.parse::<Token![,]>()?;
+    }
+
+    Ok(attrs)
+}
+
+// Log statements run inside `span.log(|log| { .. })`, the closure is only
+// called when the span is sampled.
+pub fn span_log(key: String, val: Expr) -> TokenStream {
+    quote! { log.field(LogField::new(#key, (#val).to_string())); }
+}
+
+// Tag statements run after the span is created, `set_tag` only calls the
+// closure when the span is sampled.
+pub fn span_tag(key: String, val: Expr) -> TokenStream {
+    if key == KIND {
+        return quote! { span.set_tag(|| Tag::new(#key, #val)); };
     }
 
-    attrs
+    // Keep the native type of the value if it implements `ToTagValue`,
+    // otherwise fall back to `Display`.
+    quote! { span.set_tag(|| Tag::new(#key, (&TagValueWrap(&(#val))).tag_value())); }
 }
 
-pub fn span_log(key: String, val: String) -> TokenStream {
-    if let Ok(expr) = parse_str::<Expr>(&val) {
-        quote! { span_logs.push(LogField::new(#key, (#expr).to_string())); }
+fn parse_fields(input: ParseStream) -> syn::Result<Vec<(String, Expr)>> {
+    let mut fields = Vec::new();
+
+    while !input.is_empty() {
+        let key = if input.peek(LitStr) {
+            input.parse::<LitStr>()?.value()
+        } else {
+            input.call(Ident::parse_any)?.to_string()
+        };
+        input.parse::<Token![=]>()?;
+        fields.push((key, input.parse::<Expr>()?));
+
+        if input.is_empty() {
+            break;
+        }
+        input.parse::<Token![,]>()?;
+    }
+
+    Ok(fields)
+}
+
+fn match_name_value(tracing_attrs: &mut TracingAttrs, ident: &Ident, lit: &Lit) -> syn::Result<()> {
+    if ident == KIND {
+        tracing_attrs.set_kind(get_lit_str(lit)?.value());
+    } else if ident == TRACING_NAME {
+        tracing_attrs.set_tracing_name(get_lit_str(lit)?.clone());
+    } else if ident == TRACING_TAGS {
+        tracing_attrs.set_tracing_tags(json_fields(ident, get_lit_str(lit)?)?);
+    } else if ident == TRACING_LOGS {
+        tracing_attrs.set_tracing_logs(json_fields(ident, get_lit_str(lit)?)?);
+    } else if ident == TRACING_CTX {
+        tracing_attrs.set_tracing_ctx(get_lit_str(lit)?.parse::<Expr>()?);
+    } else if ident == FIELDS_MAX_LEN {
+        tracing_attrs.set_fields_len(get_lit_int(lit)?);
+    } else if ident == TRACING_IF {
+        tracing_attrs.set_tracing_if(get_lit_str(lit)?.parse::<Expr>()?);
+    } else if ident == SLOW_THRESHOLD_MS {
+        tracing_attrs.set_slow_threshold_ms(get_lit_int(lit)? as u64);
+    } else if ident == SAMPLE_RATE {
+        tracing_attrs.set_sample_rate(get_sample_rate(lit)?);
+    } else if ident == SPAN_KIND {
+        let span_kind = get_lit_str(lit)?;
+        if !SPAN_KINDS.contains(&span_kind.value().as_str()) {
+            return Err(syn::Error::new_spanned(
+                span_kind,
+                "expected one of `client`, `server`, `producer`, `consumer`, `internal`",
+            ));
+        }
+        tracing_attrs.set_span_kind(span_kind.value());
     } else {
-        quote! { span_logs.push(LogField::new(#key, #val)); }
+        return Err(unknown_option(ident));
     }
+
+    Ok(())
 }
 
-pub fn span_tag(key: String, val: String) -> TokenStream {
-    if key == KIND {
-        return quote! { span_tags.push(Tag::new(#key, #val)); };
+fn match_flag(tracing_attrs: &mut TracingAttrs, ident: &Ident) -> syn::Result<()> {
+    if ident == TRACING_RET {
+        tracing_attrs.set_ret_format(FieldFormat::Debug);
+    } else if ident == TRACING_ERR {
+        tracing_attrs.set_err(true);
+    } else if ident == TRACING_NO_ERR {
+        tracing_attrs.set_err(false);
+    } else if ident == TRACING_ROOT {
+        tracing_attrs.set_span_ref(ident, SpanRef::Root)?;
+    } else if ident == TRACING_FOLLOWS_FROM {
+        tracing_attrs.set_span_ref(ident, SpanRef::FollowsFrom)?;
+    } else if TRACING_OPTIONS.iter().any(|option| ident == option) {
+        return Err(syn::Error::new(
+            ident.span(),
+            format!("expected `{} = ..` or `{}(..)`", ident, ident),
+        ));
+    } else {
+        return Err(unknown_option(ident));
     }
 
-    if let Ok(expr) = parse_str::<Expr>(&val) {
-        quote! { span_tags.push(Tag::new(#key, (#expr).to_string())); }
+    Ok(())
+}
+
+fn match_list(
+    tracing_attrs: &mut TracingAttrs,
+    ident: &Ident,
+    input: ParseStream,
+) -> syn::Result<()> {
+    if ident == TRACING_TAGS {
+        tracing_attrs.set_tracing_tags(parse_fields(input)?);
+    } else if ident == TRACING_LOGS {
+        tracing_attrs.set_tracing_logs(parse_fields(input)?);
+    } else if ident == TRACING_FIELDS {
+        let (all, fields) = parse_arg_fields(input)?;
+        tracing_attrs.set_fields(all, fields);
+    } else if ident == TRACING_RET {
+        tracing_attrs.set_ret_format(parse_field_format(&input.parse::<Ident>()?)?);
+    } else if ident == TRACING_ERR {
+        tracing_attrs.set_err(true);
+        tracing_attrs.set_err_record(parse_err_record(input)?);
+    } else if ident == TRACING_SKIP {
+        let skip = input.parse_terminated::<Ident, Token![,]>(Ident::parse_any)?;
+        tracing_attrs.set_skip(skip.into_iter().collect());
+    } else if ident == KIND || ident == TRACING_NAME || ident == TRACING_CTX || ident == SPAN_KIND {
+        return Err(syn::Error::new(
+            ident.span(),
+            format!(
+                "`{}` expects a string literal, e.g. `{} = \"..\"`",
+                ident, ident
+            ),
+        ));
     } else {
-        quote! { span_tags.push(Tag::new(#key, #val)); }
-    }
-}
-
-fn match_attr(tracing_attrs: &mut TracingAttrs, input: &NestedMeta) {
-    match input {
-        NestedMeta::Meta(data) => match data {
-            Meta::NameValue(name_value) => {
-                let ident = &name_value
-                    .path
-                    .segments
-                    .first()
-                    .expect("there must be at least 1 segment")
-                    .ident;
-
-                if ident == KIND {
-                    tracing_attrs.set_kind(get_lit_str(&name_value.lit));
-                } else if ident == TRACING_NAME {
-                    tracing_attrs.set_tracing_name(get_lit_str(&name_value.lit));
-                } else if ident == TRACING_TAGS {
-                    tracing_attrs.set_tracing_tags(parse_json(&get_lit_str(&name_value.lit)));
-                } else if ident == TRACING_LOGS {
-                    tracing_attrs.set_tracing_logs(parse_json(&get_lit_str(&name_value.lit)));
-                } else {
-                    panic!("");
-                }
-            }
-            _ => unreachable!("name_value"),
-        },
-        _ => unreachable!("meta"),
+        return Err(unknown_option(ident));
+    }
+
+    Ok(())
+}
+
+// `fields(all, hash = debug, height)`, each argument is recorded as `value`
+// unless `display` or `debug` is given.
+fn parse_arg_fields(input: ParseStream) -> syn::Result<(bool, Vec<(Ident, FieldFormat)>)> {
+    let mut all = false;
+    let mut fields = Vec::new();
+
+    while !input.is_empty() {
+        let ident = input.call(Ident::parse_any)?;
+
+        if ident == FIELDS_ALL {
+            all = true;
+        } else if input.peek(Token![=]) {
+            input.parse::<Token![=]>()?;
+            let format = parse_field_format(&input.parse::<Ident>()?)?;
+            fields.push((ident, format));
+        } else {
+            fields.push((ident, FieldFormat::Value));
+        }
+
+        if input.is_empty() {
+            break;
+        }
+        input.parse::<Token![,]>()?;
+    }
+
+    Ok((all, fields))
+}
+
+// `err(msg, source)`, only the listed parts of the error are recorded.
+fn parse_err_record(input: ParseStream) -> syn::Result<ErrRecord> {
+    let mut record = ErrRecord {
+        msg:    false,
+        kind:   false,
+        source: false,
+        debug:  false,
     };
+
+    let parts = input.parse_terminated::<Ident, Token![,]>(Ident::parse_any)?;
+    for part in parts.iter() {
+        if part == "msg" {
+            record.msg = true;
+        } else if part == "kind" {
+            record.kind = true;
+        } else if part == "source" {
+            record.source = true;
+        } else if part == "debug" {
+            record.debug = true;
+        } else {
+            return Err(syn::Error::new(
+                part.span(),
+                "expected one of `msg`, `kind`, `source`, `debug`",
+            ));
+        }
+    }
+
+    Ok(record)
 }
 
-fn get_lit_str(lit: &Lit) -> String {
-    match lit {
-        Lit::Str(value) => value.value(),
-        _ => unreachable!("lit_str"),
+fn parse_field_format(ident: &Ident) -> syn::Result<FieldFormat> {
+    if ident == "value" {
+        Ok(FieldFormat::Value)
+    } else if ident == "display" {
+        Ok(FieldFormat::Display)
+    } else if ident == "debug" {
+        Ok(FieldFormat::Debug)
+    } else {
+        Err(syn::Error::new(
+            ident.span(),
+            "expected one of `value`, `display`, `debug`",
+        ))
     }
 }
 
-fn parse_json(input: &str) -> HashMap<String, String> {
-    serde_json::from_str::<HashMap<String, String>>(&transfer_string(input.to_string()))
-        .expect("deserialize json error")
+fn unknown_option(ident: &Ident) -> syn::Error {
+    let options = TRACING_OPTIONS
+        .iter()
+        .map(|option| format!("`{}`", option))
+        .collect::<Vec<_>>();
+
+    syn::Error::new(
+        ident.span(),
+        format!(
+            "unknown tracing_span option `{}`, expected one of {}",
+            ident,
+            options.join(", ")
+        ),
+    )
 }
 
-fn transfer_string(input: String) -> String {
-    let mut res = input.clone();
-    let mut temp = Vec::new();
-    for (index, elem) in input.chars().enumerate() {
-        if elem == '\'' {
-            temp.push(index);
-        }
+fn get_lit_int(lit: &Lit) -> syn::Result<usize> {
+    match lit {
+        Lit::Int(value) => value.base10_parse::<usize>(),
+        _ => Err(syn::Error::new_spanned(lit, "expected integer literal")),
+    }
+}
+
+fn get_sample_rate(lit: &Lit) -> syn::Result<f64> {
+    let rate = match lit {
+        Lit::Float(value) => value.base10_parse::<f64>()?,
+        Lit::Int(value) => value.base10_parse::<f64>()?,
+        _ => return Err(syn::Error::new_spanned(lit, "expected float literal")),
+    };
+
+    if !(0.0..=1.0).contains(&rate) {
+        return Err(syn::Error::new_spanned(
+            lit,
+            "`sample_rate` must be in `[0.0, 1.0]`",
+        ));
     }
 
-    for index in temp.into_iter() {
-        res.replace_range(index..index + 1, "\"");
+    Ok(rate)
+}
+
+fn get_lit_str(lit: &Lit) -> syn::Result<&LitStr> {
+    match lit {
+        Lit::Str(value) => Ok(value),
+        _ => Err(syn::Error::new_spanned(lit, "expected string literal")),
     }
-    res
+}
+
+// Legacy values are parsed as expressions, and kept as string literals if
+// they are not valid expressions.
+fn json_fields(ident: &Ident, lit: &LitStr) -> syn::Result<Vec<(String, Expr)>> {
+    let json = parse_json(&lit.value()).map_err(|e| {
+        syn::Error::new(
+            lit.span(),
+            format!(
+                "invalid `{{'key': 'value'}}` string: {}, consider the `{}(key = value)` form",
+                e, ident
+            ),
+        )
+    })?;
+
+    let mut fields = json
+        .into_iter()
+        .map(|(key, val)| match parse_str::<Expr>(&val) {
+            Ok(expr) => (key, expr),
+            Err(_) => (key, str_expr(&val)),
+        })
+        .collect::<Vec<_>>();
+    fields.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(fields)
+}
+
+fn str_expr(val: &str) -> Expr {
+    Expr::Lit(ExprLit {
+        attrs: Vec::new(),
+        lit:   Lit::Str(LitStr::new(val, Span::call_site())),
+    })
+}
+
+fn parse_json(input: &str) -> serde_json::Result<HashMap<String, String>> {
+    serde_json::from_str::<HashMap<String, String>>(&transfer_string(input.to_string()))
+}
+
+fn transfer_string(input: String) -> String {
+    input.replace('\'', "\"")
 }
 
 #[cfg(test)]
 mod test {
-    use super::transfer_string;
+    use quote::quote;
+    use syn::parse::Parser;
+    use syn::LitStr;
+
+    use super::{parse_attrs, transfer_string, ErrRecord, FieldFormat, SpanRef};
 
     #[test]
     fn test_transfer_string() {
@@ -154,5 +610,129 @@ mod test {
             transfer_string(String::from("{'a': 'b', 'c': 'd'}")),
             "{\"a\": \"b\", \"c\": \"d\"}",
         );
+        assert_eq!(
+            transfer_string(String::from("{'é': 'x', 'c': 'd'}")),
+            "{\"é\": \"x\", \"c\": \"d\"}",
+        );
+
+        let attrs = parse_attrs
+            .parse2(quote! { kind = "main", tags = "{'é': '1'}" })
+            .unwrap();
+        let tags = attrs
+            .get_tags()
+            .into_iter()
+            .map(|(key, val)| (key, quote! { #val }.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(tags, vec![
+            ("kind".to_owned(), "\"main\"".to_owned()),
+            ("é".to_owned(), "1".to_owned())
+        ]);
+    }
+
+    #[test]
+    fn test_parse_native_attrs() {
+        let attrs = parse_attrs
+            .parse2(quote! {
+                kind = "consensus",
+                tags(height = height, "span.kind" = "server"),
+                logs(tx_count = txs.len())
+            })
+            .unwrap();
+
+        assert_eq!(attrs.kind, "consensus");
+        let tags = attrs
+            .get_tags()
+            .into_iter()
+            .map(|(key, val)| (key, quote! { #val }.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(tags, vec![
+            ("kind".to_string(), "\"consensus\"".to_string()),
+            ("height".to_string(), "height".to_string()),
+            ("span.kind".to_string(), "\"server\"".to_string()),
+        ]);
+        assert_eq!(attrs.get_logs()[0].0, "tx_count");
+    }
+
+    #[test]
+    fn test_parse_legacy_attrs() {
+        let attrs = parse_attrs
+            .parse2(quote! { name = "commit", tags = "{'c': 'm + 3', 'a': 'b'}" })
+            .unwrap();
+
+        assert_eq!(
+            attrs.tracing_name.as_ref().map(LitStr::value),
+            Some("commit".to_string())
+        );
+        let keys = attrs
+            .get_tags()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec!["kind", "a", "c"]);
+    }
+
+    #[test]
+    fn test_parse_fields() {
+        let attrs = parse_attrs
+            .parse2(quote! { fields(height, hash = debug), fields_max_len = 64 })
+            .unwrap();
+
+        assert!(!attrs.is_fields_all());
+        assert_eq!(attrs.fields[1].0, "hash");
+        assert_eq!(attrs.fields[1].1, FieldFormat::Debug);
+        assert_eq!(attrs.fields_len, Some(64));
+
+        let attrs = parse_attrs.parse2(quote! { skip(txs) }).unwrap();
+        assert!(attrs.is_fields_all());
+    }
+
+    #[test]
+    fn test_parse_flags() {
+        let attrs = parse_attrs.parse2(quote! { ret(display), err }).unwrap();
+        assert_eq!(attrs.ret_format, Some(FieldFormat::Display));
+        assert_eq!(attrs.err, Some(true));
+
+        let attrs = parse_attrs.parse2(quote! { kind = "a", no_err }).unwrap();
+        assert_eq!(attrs.ret_format, None);
+        assert_eq!(attrs.err, Some(false));
+        assert_eq!(attrs.err_record, ErrRecord::default());
+
+        let attrs = parse_attrs.parse2(quote! { err(source, debug) }).unwrap();
+        assert_eq!(attrs.err, Some(true));
+        assert_eq!(attrs.err_record, ErrRecord {
+            msg:    false,
+            kind:   false,
+            source: true,
+            debug:  true,
+        });
+    }
+
+    #[test]
+    fn test_parse_span_ref() {
+        let attrs = parse_attrs
+            .parse2(quote! { kind = "network", span_kind = "producer", follows_from })
+            .unwrap();
+        assert_eq!(attrs.span_ref, SpanRef::FollowsFrom);
+        assert_eq!(attrs.get_tags()[1].0, "span.kind");
+
+        assert!(parse_attrs.parse2(quote! { root, follows_from }).is_err());
+        assert!(parse_attrs.parse2(quote! { span_kind = "sender" }).is_err());
+    }
+
+    #[test]
+    fn test_parse_sampling() {
+        let attrs = parse_attrs
+            .parse2(quote! { if = "height % 100 == 0", sample_rate = 0.01 })
+            .unwrap();
+        assert!(attrs.tracing_if.is_some());
+        assert_eq!(attrs.sample_rate, Some(0.01));
+
+        let attrs = parse_attrs
+            .parse2(quote! { slow_threshold_ms = 200 })
+            .unwrap();
+        assert_eq!(attrs.slow_threshold_ms, Some(200));
+
+        assert!(parse_attrs.parse2(quote! { sample_rate = 2 }).is_err());
+        assert!(parse_attrs.parse2(quote! { if = "height +" }).is_err());
     }
 }
@@ -0,0 +1,252 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{Expr, ExprUnary, FnArg, Ident, Pat, PatType, Signature, Type, TypePath, UnOp};
+
+static CONTEXT_CRATE: &str = "creep";
+static CONTEXT_TYPE: &str = "Context";
+static CONTEXT_NAME: &str = "ctx";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum CtxRef {
+    Owned,
+    Ref,
+    RefMut,
+}
+
+/// Where the generated code reads the parent span from, and how the child
+/// context is passed to the function body.
+pub enum CtxSource {
+    Arg {
+        ident:    Ident,
+        is_mut:   bool,
+        ref_kind: CtxRef,
+    },
+    // A place like `self.ctx` or `req.ctx`, the child context is written in
+    // it while the body runs.
+    Place(Box<Expr>),
+}
+
+impl CtxSource {
+    /// The context argument, if the context is not read from a place.
+    pub fn arg_ident(&self) -> Option<&Ident> {
+        match self {
+            CtxSource::Arg { ident, .. } => Some(ident),
+            CtxSource::Place(_) => None,
+        }
+    }
+
+    /// The expression of the `creep::Context` holding the parent span.
+    pub fn parent_ctx(&self) -> TokenStream {
+        match self {
+            CtxSource::Arg { ident, .. } => quote! { #ident },
+            CtxSource::Place(expr) => quote! { (#expr) },
+        }
+    }
+
+    /// Whether the body sees a borrowed context, the child context is then
+    /// owned by the caller or by the returned future.
+    pub fn is_borrowed(&self) -> bool {
+        match self {
+            CtxSource::Arg { ref_kind, .. } => *ref_kind != CtxRef::Owned,
+            CtxSource::Place(_) => true,
+        }
+    }
+
+    /// Whether the child context is written in the caller's context, and the
+    /// parent span restored after the body.
+    pub fn needs_restore(&self) -> bool {
+        match self {
+            CtxSource::Arg { ref_kind, .. } => *ref_kind == CtxRef::RefMut,
+            CtxSource::Place(_) => true,
+        }
+    }
+
+    /// Statements passing the child context to the body: an owned or shared
+    /// context argument is shadowed, a `&mut` one or a place is written in.
+    pub fn inject_child(&self) -> TokenStream {
+        let (ident, is_mut, ref_kind) = match self {
+            CtxSource::Arg {
+                ident,
+                is_mut,
+                ref_kind,
+            } => (ident, *is_mut, *ref_kind),
+            CtxSource::Place(expr) => {
+                return quote! { let __muta_apm_parent_keys = span.enter_ctx(&mut #expr); };
+            }
+        };
+        let mutability = if is_mut {
+            quote! { mut }
+        } else {
+            quote! {}
+        };
+
+        match ref_kind {
+            CtxRef::Owned => quote! {
+                let #mutability #ident = match span.child_ctx(&#ident) {
+                    Some(__muta_apm_child_ctx) => __muta_apm_child_ctx,
+                    None => #ident,
+                };
+            },
+            CtxRef::Ref => quote! {
+                let __muta_apm_child_ctx = span.child_ctx(#ident);
+                let #mutability #ident = match __muta_apm_child_ctx.as_ref() {
+                    Some(__muta_apm_child_ctx) => __muta_apm_child_ctx,
+                    None => #ident,
+                };
+            },
+            CtxRef::RefMut => quote! {
+                let __muta_apm_parent_keys = span.enter_ctx(#ident);
+            },
+        }
+    }
+
+    /// Statements setting the parent span back in the caller's context, see
+    /// `needs_restore`.
+    pub fn restore_parent(&self) -> TokenStream {
+        match self {
+            CtxSource::Arg {
+                ident,
+                ref_kind: CtxRef::RefMut,
+                ..
+            } => quote! { __muta_apm_parent_keys.restore(#ident); },
+            CtxSource::Place(expr) => quote! { __muta_apm_parent_keys.restore(&mut #expr); },
+            CtxSource::Arg { .. } => quote! {},
+        }
+    }
+
+    /// Statements reborrowing a `&mut` context argument, so that it's still
+    /// usable after a body moving it.
+    pub fn reborrow(&self) -> TokenStream {
+        match self {
+            CtxSource::Arg {
+                ident,
+                is_mut,
+                ref_kind: CtxRef::RefMut,
+            } => {
+                let mutability = if *is_mut {
+                    quote! { mut }
+                } else {
+                    quote! {}
+                };
+                quote! { let #mutability #ident = &mut *#ident; }
+            }
+            _ => quote! {},
+        }
+    }
+}
+
+/// Use the `ctx = ".."` expression if given, otherwise the first argument of
+/// type `Context`, `&Context` or `&mut Context`, otherwise the argument named
+/// `ctx`.
+pub fn find_ctx(sig: &Signature, ctx_expr: Option<Expr>) -> syn::Result<CtxSource> {
+    let args = sig
+        .inputs
+        .iter()
+        .filter_map(|arg| match arg {
+            FnArg::Typed(PatType { pat, ty, .. }) => match pat.as_ref() {
+                Pat::Ident(pat_ident) => Some((pat_ident, ty.as_ref())),
+                _ => None,
+            },
+            FnArg::Receiver(_) => None,
+        })
+        .collect::<Vec<_>>();
+
+    let found = match ctx_expr {
+        Some(expr) => {
+            let arg = ctx_arg_name(&expr)
+                .and_then(|name| args.iter().find(|(pat, _)| pat.ident == *name));
+            match arg {
+                Some(arg) => Some(arg),
+                None => return ctx_place(expr),
+            }
+        }
+        None => args
+            .iter()
+            .find(|(_, ty)| is_context_type(ty))
+            .or_else(|| args.iter().find(|(pat, _)| pat.ident == CONTEXT_NAME)),
+    };
+
+    match found {
+        Some((pat_ident, ty)) => Ok(CtxSource::Arg {
+            ident:    pat_ident.ident.clone(),
+            is_mut:   pat_ident.mutability.is_some(),
+            ref_kind: ctx_ref_kind(ty),
+        }),
+        None => Err(syn::Error::new(
+            sig.ident.span(),
+            "no `creep::Context` argument found, add a `ctx: Context` argument or point to \
+             one with `ctx = \"..\"`",
+        )),
+    }
+}
+
+fn ctx_arg_name(expr: &Expr) -> Option<&Ident> {
+    match expr {
+        Expr::Path(path) if path.qself.is_none() => path.path.get_ident(),
+        _ => None,
+    }
+}
+
+// A `ctx = ".."` expression which isn't an argument must be a place.
+fn ctx_place(expr: Expr) -> syn::Result<CtxSource> {
+    check_place(&expr)?;
+    Ok(CtxSource::Place(Box::new(expr)))
+}
+
+/// Error unless the expression is a place the child context can be written
+/// in, like `self.ctx` or `req.ctx`.
+pub fn check_place(expr: &Expr) -> syn::Result<()> {
+    if is_place(expr) {
+        Ok(())
+    } else {
+        Err(syn::Error::new_spanned(
+            expr,
+            "the context must be a variable or a place like `self.ctx`, bind it to a variable \
+             first",
+        ))
+    }
+}
+
+fn is_place(expr: &Expr) -> bool {
+    match expr {
+        Expr::Path(_) => true,
+        Expr::Field(field) => is_place(&field.base),
+        Expr::Index(index) => is_place(&index.expr),
+        Expr::Paren(paren) => is_place(&paren.expr),
+        Expr::Unary(ExprUnary {
+            op: UnOp::Deref(_),
+            expr,
+            ..
+        }) => is_place(expr),
+        _ => false,
+    }
+}
+
+fn ctx_ref_kind(ty: &Type) -> CtxRef {
+    match ty {
+        Type::Reference(reference) if reference.mutability.is_some() => CtxRef::RefMut,
+        Type::Reference(_) => CtxRef::Ref,
+        _ => CtxRef::Owned,
+    }
+}
+
+// `Context` or `creep::Context`, not `std::task::Context` nor one with
+// generic arguments like `Context<'_>`.
+fn is_context_type(ty: &Type) -> bool {
+    match ty {
+        Type::Reference(reference) => is_context_type(&reference.elem),
+        Type::Path(TypePath { qself: None, path }) => {
+            let mut segments = path.segments.iter().rev();
+            let is_context = segments
+                .next()
+                .map(|seg| seg.ident == CONTEXT_TYPE && seg.arguments.is_empty())
+                .unwrap_or(false);
+
+            match segments.next() {
+                Some(seg) => is_context && seg.ident == CONTEXT_CRATE,
+                None => is_context,
+            }
+        }
+        _ => false,
+    }
+}
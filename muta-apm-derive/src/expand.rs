@@ -1,99 +1,130 @@
 use proc_macro::TokenStream;
+use proc_macro2::TokenTree;
 use quote::quote;
+use syn::parse::Parser;
+use syn::punctuated::Punctuated;
 use syn::{
-    parse_macro_input, AttributeArgs, GenericArgument, ItemFn, PathArguments, ReturnType,
-    TraitBound, Type, TypeParamBound, TypePath, TypeTraitObject,
+    parse_macro_input, parse_quote, GenericArgument, ItemFn, Path, PathArguments, ReturnType,
+    Signature, Token, TraitBound, Type, TypeParamBound, TypePath, TypeTraitObject,
 };
 
-use crate::attr_parse::{parse_attrs, span_log, span_tag};
+use crate::attr_parse::{
+    parse_attrs, span_log, span_tag, ErrRecord, FieldFormat, SpanRef, TracingAttrs,
+};
+use crate::context::{find_ctx, CtxSource};
+use crate::fields::field_tags;
+use crate::name::span_name;
+
+pub const DEFAULT_MAX_LEN: usize = 256;
 
-struct PinBoxFutRet {
-    is_pin_box_fut:    bool,
-    is_fut_ret_result: bool,
-    ret_ty:            proc_macro2::TokenStream,
+/// How the function hands its output back to the caller.
+enum FuncRet {
+    // Returned directly, including by `async fn`.
+    Value(Type),
+    // `Pin<Box<dyn Future<Output = T>>>` or `BoxFuture<'_, T>`, as returned
+    // by async-trait.
+    PinBoxFut(Option<Type>),
+    // `impl Future<Output = T>`.
+    ImplFut(Option<Type>),
 }
 
-impl Default for PinBoxFutRet {
-    fn default() -> Self {
-        PinBoxFutRet {
-            is_pin_box_fut:    false,
-            is_fut_ret_result: false,
-            ret_ty:            quote! {},
+impl FuncRet {
+    fn new(sig: &Signature) -> Self {
+        let ty = match &sig.output {
+            ReturnType::Default => return FuncRet::Value(parse_quote! { () }),
+            ReturnType::Type(_, ty) => ty.as_ref(),
+        };
+
+        // The output of an `async fn` is already awaited by the caller.
+        if sig.asyncness.is_some() {
+            return FuncRet::Value(ty.clone());
+        }
+
+        match ty {
+            Type::Path(path) => match pin_box_fut_output(path) {
+                Some(output) => FuncRet::PinBoxFut(output),
+                None => FuncRet::Value(ty.clone()),
+            },
+            // The returned future is wrapped in an `async` block, which
+            // is neither `Unpin` nor `Sync`, so only plain `Future` and
+            // `Send` bounds are accepted.
+            Type::ImplTrait(impl_trait) => match fut_output(&impl_trait.bounds) {
+                Some(output)
+                    if impl_trait.bounds.iter().all(|bound| match bound {
+                        TypeParamBound::Trait(bound) => {
+                            is_last_seg(&bound.path, "Future") || is_last_seg(&bound.path, "Send")
+                        }
+                        TypeParamBound::Lifetime(_) => true,
+                    }) =>
+                {
+                    FuncRet::ImplFut(output)
+                }
+                _ => FuncRet::Value(ty.clone()),
+            },
+            _ => FuncRet::Value(ty.clone()),
         }
     }
 }
 
-impl PinBoxFutRet {
-    fn set_is_pin_box(&mut self, is_pin_box: bool) {
-        self.is_pin_box_fut = is_pin_box;
-    }
+fn is_last_seg(path: &Path, ident: &str) -> bool {
+    path.segments
+        .last()
+        .map(|seg| seg.ident == ident)
+        .unwrap_or(false)
+}
 
-    fn set_is_ret_result(&mut self, is_ret_result: bool) {
-        self.is_fut_ret_result = is_ret_result;
+fn last_type_arg(path: &Path) -> Option<&Type> {
+    match &path.segments.last()?.arguments {
+        PathArguments::AngleBracketed(args) => args.args.iter().rev().find_map(|arg| match arg {
+            GenericArgument::Type(ty) => Some(ty),
+            _ => None,
+        }),
+        _ => None,
     }
+}
 
-    fn set_ret_type(&mut self, ret_type: proc_macro2::TokenStream) {
-        self.ret_ty = ret_type;
+// `Some(output)` if the path is a boxed future, the output is `None` if the
+// `Output` binding can't be found.
+fn pin_box_fut_output(path: &TypePath) -> Option<Option<Type>> {
+    if is_last_seg(&path.path, "BoxFuture") || is_last_seg(&path.path, "LocalBoxFuture") {
+        return Some(last_type_arg(&path.path).cloned());
+    }
+    if !is_last_seg(&path.path, "Pin") {
+        return None;
     }
-}
 
-fn is_ret_pin_box_fut_result(ret_ty: &ReturnType) -> PinBoxFutRet {
-    let expect_ty = match ret_ty {
-        syn::ReturnType::Type(_, ty) => ty,
-        _ => return PinBoxFutRet::default(),
+    let boxed = match last_type_arg(&path.path)? {
+        Type::Path(boxed) if is_last_seg(&boxed.path, "Box") => boxed,
+        _ => return None,
     };
 
-    let expect_pin = match *(expect_ty.clone()) {
-        Type::Path(TypePath { qself: _, path }) => {
-            let last_seg = path.segments.last().cloned();
-            match last_seg.map(|ls| (ls.ident.clone(), ls)) {
-                Some((ls_ident, ls)) if ls_ident.to_string() == "Pin" => ls,
-                _ => return PinBoxFutRet::default(),
-            }
+    match last_type_arg(&boxed.path)? {
+        Type::TraitObject(TypeTraitObject { bounds, .. }) => fut_output(bounds),
+        _ => None,
+    }
+}
+
+// Find the `Future` bound whatever the order of the bounds, and its `Output`
+// binding whatever the order of the arguments.
+fn fut_output(bounds: &Punctuated<TypeParamBound, Token![+]>) -> Option<Option<Type>> {
+    let fut = bounds.iter().find_map(|bound| match bound {
+        TypeParamBound::Trait(TraitBound { path, .. }) if is_last_seg(path, "Future") => {
+            path.segments.last()
         }
-        _ => return PinBoxFutRet::default(),
-    };
+        _ => None,
+    })?;
 
-    let expect_box = match &expect_pin.arguments {
-        syn::PathArguments::AngleBracketed(wrapper) => match wrapper.args.last() {
-            Some(GenericArgument::Type(syn::Type::Path(TypePath { qself: _, path }))) => {
-                match path.segments.last().map(|ls| (ls.ident.clone(), ls)) {
-                    Some((ls_ident, ls)) if ls_ident.to_string() == "Box" => ls,
-                    _ => return PinBoxFutRet::default(),
-                }
+    let output = match &fut.arguments {
+        PathArguments::AngleBracketed(args) => args.args.iter().find_map(|arg| match arg {
+            GenericArgument::Binding(binding) if binding.ident == "Output" => {
+                Some(binding.ty.clone())
             }
-            _ => return PinBoxFutRet::default(),
-        },
-        _ => return PinBoxFutRet::default(),
+            _ => None,
+        }),
+        _ => None,
     };
 
-    // Has Future trait bound
-    match &expect_box.arguments {
-        syn::PathArguments::AngleBracketed(wrapper) => match wrapper.args.last() {
-            Some(GenericArgument::Type(syn::Type::TraitObject(TypeTraitObject {
-                dyn_token: _,
-                bounds,
-            }))) => {
-                let mut fut_ret = PinBoxFutRet::default();
-
-                for bound in bounds.iter() {
-                    if let TypeParamBound::Trait(TraitBound { path, .. }) = bound {
-                        if let Some(arg) = path.segments.last() {
-                            if arg.ident.to_string() == "Future" {
-                                fut_ret.set_is_pin_box(true);
-                                let is_ret_result = is_fut_ret_result(&arg.arguments, &mut fut_ret);
-                                fut_ret.set_is_ret_result(is_ret_result);
-                                break;
-                            }
-                        }
-                    }
-                }
-                fut_ret
-            }
-            _ => PinBoxFutRet::default(),
-        },
-        _ => PinBoxFutRet::default(),
-    }
+    Some(output)
 }
 
 pub fn func_expand(attr: TokenStream, func: TokenStream) -> TokenStream {
@@ -106,189 +137,512 @@ pub fn func_expand(attr: TokenStream, func: TokenStream) -> TokenStream {
     let func_inputs = &func_decl.inputs;
     let func_output = &func_decl.output;
     let func_async = func_decl.asyncness;
-    let is_func_ret_result = is_return_result(func_output);
-    let func_ret_ty = match func_output {
-        ReturnType::Default => quote! { () },
-        ReturnType::Type(_, ty) => quote! { #ty },
+    let func_ret = FuncRet::new(func_decl);
+
+    let tracing_attrs = match parse_attrs.parse(attr) {
+        Ok(attrs) => attrs,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let ctx_source = match find_ctx(func_decl, tracing_attrs.get_tracing_ctx()) {
+        Ok(ctx_source) => ctx_source,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let field_tag_stmts = match field_tags(func_decl, ctx_source.arg_ident(), &tracing_attrs) {
+        Ok(stmts) => stmts,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    // The returned future can't borrow the place to restore the parent span
+    // once it completes.
+    if let (CtxSource::Place(expr), FuncRet::PinBoxFut(_))
+    | (CtxSource::Place(expr), FuncRet::ImplFut(_)) = (&ctx_source, &func_ret)
+    {
+        return syn::Error::new_spanned(
+            expr,
+            "a function returning a future must take the context as an argument",
+        )
+        .to_compile_error()
+        .into();
+    }
+    let parent_ctx = ctx_source.parent_ctx();
+    let restore_parent_ctx = ctx_source.restore_parent();
+    let reborrow_ctx = ctx_source.reborrow();
+    // A borrowed context is injected in the returned future, which then owns
+    // the child context.
+    let (inject_child_ctx, fut_inject_child_ctx) = match func_ret {
+        FuncRet::PinBoxFut(_) | FuncRet::ImplFut(_) if ctx_source.is_borrowed() => {
+            (quote! {}, ctx_source.inject_child())
+        }
+        _ => (ctx_source.inject_child(), quote! {}),
+    };
+    let trace_name = match span_name(func_decl, &tracing_attrs) {
+        Ok(trace_name) => trace_name,
+        Err(e) => return e.to_compile_error().into(),
     };
 
-    let tracing_attrs = parse_attrs(parse_macro_input!(attr as AttributeArgs));
-    let kind = tracing_attrs.kind.clone();
-    let trace_name = if let Some(name) = tracing_attrs.get_tracing_name() {
-        kind + "." + &name
-    } else {
-        kind + "." + &func_name.to_string()
+    // The attributes are still checked, so that they don't only fail in
+    // builds with tracing.
+    if cfg!(feature = "disable-tracing") {
+        let res = quote! {
+            #[allow(unused_variables)]
+            #func
+        };
+        return res.into();
+    }
+
+    let max_len = tracing_attrs.fields_len.unwrap_or(DEFAULT_MAX_LEN);
+
+    let err = tracing_attrs.err;
+    let ret_format = tracing_attrs.ret_format;
+    let err_record = tracing_attrs.err_record;
+    let func_block_report = match func_ret {
+        // Workaround for async-trait, which return Pin<Box<dyn Future>>, and
+        // cause tracing span object be dropped too early.
+        FuncRet::PinBoxFut(output) => {
+            let report_ret = report_ret(
+                ret_kind(output.as_ref(), err),
+                ret_format,
+                err_record,
+                max_len,
+            );
+            let ret_ty = output.map(|ty| quote! { : #ty });
+
+            quote! {
+                Box::pin(async move {
+                    #fut_inject_child_ctx
+                    let fut = {
+                        #reborrow_ctx
+                        #func_block
+                    };
+                    let ret #ret_ty = muta_apm::panic::CatchPanic::new(&mut span, fut).await;
+                    #restore_parent_ctx
+                    #report_ret
+                    ret
+                })
+            }
+        }
+        // The body runs when called, the span is finished once the returned
+        // future completes. With a borrowed context, the body runs on the
+        // first poll, from the future owning the child context.
+        FuncRet::ImplFut(output) if ctx_source.is_borrowed() => {
+            let report_ret = report_ret(
+                ret_kind(output.as_ref(), err),
+                ret_format,
+                err_record,
+                max_len,
+            );
+            let ret_ty = output.map(|ty| quote! { : #ty });
+
+            quote! {
+                async move {
+                    #fut_inject_child_ctx
+                    let fut = {
+                        #reborrow_ctx
+                        async move {
+                            let fut = #func_block;
+                            fut.await
+                        }
+                    };
+                    let ret #ret_ty = muta_apm::panic::CatchPanic::new(&mut span, fut).await;
+                    #restore_parent_ctx
+                    #report_ret
+                    ret
+                }
+            }
+        }
+        FuncRet::ImplFut(output) => {
+            let report_ret = report_ret(
+                ret_kind(output.as_ref(), err),
+                ret_format,
+                err_record,
+                max_len,
+            );
+            let ret_ty = output.map(|ty| quote! { : #ty });
+
+            quote! {
+                let fut = {
+                    let _entered = span.enter();
+                    #func_block
+                };
+                async move {
+                    let ret #ret_ty = muta_apm::panic::CatchPanic::new(&mut span, fut).await;
+                    #report_ret
+                    ret
+                }
+            }
+        }
+        FuncRet::Value(ty) => {
+            let report_ret = report_ret(ret_kind(Some(&ty), err), ret_format, err_record, max_len);
+            // `impl Trait` can't be written in a let binding. The return type
+            // of the body closure is written too, for the coercions of the
+            // returned value, like `Box<E>` to `Box<dyn Error>`.
+            let (ret_ty, closure_ret_ty) = match ty {
+                Type::ImplTrait(_) => (quote! {}, quote! {}),
+                _ => (quote! { : #ty }, quote! { -> #ty }),
+            };
+
+            if func_async.is_some() {
+                // Panics are reported from the poll of the body, the future
+                // of an `async fn` may be dropped after unwinding. A place
+                // is only borrowed by the body, to be restored after it.
+                let body_async = match ctx_source {
+                    CtxSource::Place(_) => quote! { async },
+                    CtxSource::Arg { .. } => quote! { async move },
+                };
+                quote! {
+                    let fut = {
+                        #reborrow_ctx
+                        #body_async #func_block
+                    };
+                    let ret #ret_ty = muta_apm::panic::CatchPanic::new(&mut span, fut).await;
+                    #restore_parent_ctx
+                    #report_ret
+                    ret
+                }
+            } else if report_ret.is_empty() && !ctx_source.needs_restore() {
+                quote! {
+                    let _entered = span.enter();
+                    #func_block
+                }
+            } else if has_mut_ref(&ty) && !ctx_source.needs_restore() {
+                // A closure can't return a `&mut` borrow of what it captures,
+                // like `&mut self`, the value isn't reported after an early
+                // return.
+                quote! {
+                    let _entered = span.enter();
+                    let ret #ret_ty = #func_block;
+                    #report_ret
+                    ret
+                }
+            } else {
+                // The body runs in a closure, so that the returned value is
+                // reported and the parent span restored after an early
+                // return.
+                quote! {
+                    let _entered = span.enter();
+                    let ret #ret_ty = (|| #closure_ret_ty #func_block)();
+                    #restore_parent_ctx
+                    #report_ret
+                    ret
+                }
+            }
+        }
+    };
+
+    let span_imports = span_imports();
+    let start_span = start_span(&trace_name, &parent_ctx, &tracing_attrs, &field_tag_stmts);
+
+    let res = quote! {
+        #[allow(
+            unused_imports,
+            unused_variables,
+            clippy::type_complexity,
+            clippy::diverging_sub_expression
+        )]
+        #func_vis #func_async fn #func_name #func_generics(#func_inputs) #func_output #where_clause {
+            #span_imports
+            #start_span
+
+            #inject_child_ctx
+
+            #func_block_report
+        }
+    };
+    res.into()
+}
+
+/// Imports used by the code generated by `start_span` and `report_ret`.
+pub fn span_imports() -> proc_macro2::TokenStream {
+    quote! {
+        use muta_apm::rustracing_jaeger::span::SpanContext;
+        use muta_apm::rustracing::tag::{Tag, TagValue};
+        use muta_apm::rustracing::log::LogField;
+        use muta_apm::tag::{DisplayTagValue as _, NativeTagValue as _, TagValueWrap};
+        use muta_apm::tag::{
+            DebugFieldValue as _, DisplayFieldValue as _, FieldValueWrap, NativeFieldValue as _,
+        };
+    }
+}
+
+/// Statements starting the span from the parent span of `parent_ctx` and
+/// binding it to `span`, a `SpanGuard`, then recording the tags and logs.
+pub fn start_span(
+    trace_name: &proc_macro2::TokenStream,
+    parent_ctx: &proc_macro2::TokenStream,
+    tracing_attrs: &TracingAttrs,
+    field_tag_stmts: &[proc_macro2::TokenStream],
+) -> proc_macro2::TokenStream {
+    let new_span = match tracing_attrs.span_ref {
+        SpanRef::Root => quote! { muta_apm::MUTA_TRACER.span(#trace_name, Vec::new()) },
+        SpanRef::ChildOf | SpanRef::FollowsFrom => {
+            let start_with_parent = if tracing_attrs.span_ref == SpanRef::ChildOf {
+                quote! { child_of_ctx }
+            } else {
+                quote! { follows_from_ctx }
+            };
+
+            quote! {
+                muta_apm::MUTA_TRACER.#start_with_parent(#trace_name, &#parent_ctx, Vec::new())
+            }
+        }
+    };
+
+    let sample = tracing_attrs
+        .sample_rate
+        .map(|rate| quote! { muta_apm::sample::sample(#rate) });
+    let start_span_if = match (tracing_attrs.tracing_if.as_ref(), sample) {
+        (Some(cond), Some(sample)) => {
+            quote! { if (#cond) && #sample { #new_span } else { None } }
+        }
+        (Some(cond), None) => quote! { if (#cond) { #new_span } else { None } },
+        (None, Some(sample)) => quote! { if #sample { #new_span } else { None } },
+        (None, None) => new_span,
+    };
+
+    let parent_keep = match tracing_attrs.span_ref {
+        SpanRef::Root => quote! { None },
+        SpanRef::ChildOf | SpanRef::FollowsFrom => quote! {
+            #parent_ctx.get::<muta_apm::guard::KeepFlag>("parent_span_keep").cloned()
+        },
+    };
+    let slow_threshold_ms = match tracing_attrs.slow_threshold_ms {
+        Some(threshold_ms) => quote! { Some(#threshold_ms) },
+        None => quote! { None },
     };
 
     let span_tag_stmts = tracing_attrs
-        .get_tag_map()
+        .get_tags()
         .into_iter()
         .map(|(key, val)| span_tag(key, val))
         .collect::<Vec<_>>();
 
     let span_log_stmts = tracing_attrs
-        .get_log_map()
+        .get_logs()
         .into_iter()
         .map(|(key, val)| span_log(key, val))
         .collect::<Vec<_>>();
+    let span_log_stmt = if span_log_stmts.is_empty() {
+        quote! {}
+    } else {
+        quote! {
+            span.log(|log| {
+                #(#span_log_stmts)*
+            });
+        }
+    };
 
-    // Workaround for async-trait, which return Pin<Box<dyn Future>>, and cause
-    // tracing span object be dropped too early.
-    let fut_return = is_ret_pin_box_fut_result(func_output);
+    quote! {
+        let span = #start_span_if;
+        // Marks the span as error if the function panics, and discards
+        // it if faster than the slow threshold.
+        let mut span = muta_apm::guard::SpanGuard::new(span, #parent_keep, #slow_threshold_ms);
 
-    let func_block = if fut_return.is_pin_box_fut {
-        if fut_return.is_fut_ret_result {
-            let ret_ty = fut_return.ret_ty;
-            quote! {
-                Box::pin(async move {
-                    let ret: #ret_ty = #func_block.await;
-
-                    match span.as_mut() {
-                        Some(span) => {
-                            match ret.as_ref() {
-                                Err(e) => {
-                                    span.set_tag(|| Tag::new("error", true));
-                                    span.log(|log| {
-                                        log.field(LogField::new(
-                                            "error_msg",
-                                            e.to_string(),
-                                        ));
-                                    });
-                                    ret
-                                }
-                                Ok(_) => {
-                                    span.set_tag(|| Tag::new("error", false));
-                                    ret
-                                }
-                            }
-                        }
-                        None => ret,
-                    }
-                })
-            }
-        } else {
+        // Tags and logs are evaluated only if the span is sampled.
+        if let Some(span) = span.as_mut() {
+            #(#span_tag_stmts)*
+            #(#field_tag_stmts)*
+            #span_log_stmt
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum RetKind {
+    Result,
+    Option,
+    Unit,
+    Plain,
+    // The type isn't written, e.g. the value of an `apm_span!` block, the
+    // span is marked as error if it turns out to be `Err` at runtime.
+    Unknown,
+}
+
+// `err` and `no_err` take precedence over the detection of `Result` types.
+pub fn ret_kind(ret_ty: Option<&Type>, err: Option<bool>) -> RetKind {
+    if err.unwrap_or_else(|| ret_ty.map(is_result_type).unwrap_or(false)) {
+        return RetKind::Result;
+    }
+
+    match ret_ty {
+        Some(Type::Tuple(tuple)) if tuple.elems.is_empty() => RetKind::Unit,
+        Some(Type::Path(path)) if is_last_seg(&path.path, "Option") => RetKind::Option,
+        _ => RetKind::Plain,
+    }
+}
+
+// Statements run on `ret` before it is returned, `Err` marks the span as
+// error, the returned value is logged if `ret` is set.
+pub fn report_ret(
+    ret_kind: RetKind,
+    ret_format: Option<FieldFormat>,
+    err_record: ErrRecord,
+    max_len: usize,
+) -> proc_macro2::TokenStream {
+    let log_value = match ret_format {
+        Some(format) => {
+            let value = match format {
+                FieldFormat::Display => quote! { format!("{}", value) },
+                FieldFormat::Debug | FieldFormat::Value => quote! { format!("{:?}", value) },
+            };
             quote! {
-                Box::pin(async move {
-                    let _ = span;
-                    #func_block.await
-                })
+                span.log(|log| {
+                    log.field(LogField::new(
+                        "return",
+                        muta_apm::tag::truncate_str(#value, #max_len),
+                    ));
+                });
             }
         }
-    } else {
-        quote! {
-            #func_block
-        }
+        None => quote! {},
     };
 
-    let func_block_report_err = if is_func_ret_result {
-        quote! {
-            let ret: #func_ret_ty = #func_block;
-
-            match span.as_mut() {
-                Some(span) => {
+    match ret_kind {
+        RetKind::Result => {
+            let report_err = report_err(err_record);
+            quote! {
+                if ret.is_err() {
+                    span.keep();
+                }
+                if let Some(span) = span.as_mut() {
                     match ret.as_ref() {
                         Err(e) => {
                             span.set_tag(|| Tag::new("error", true));
-                            span.log(|log| {
-                                log.field(LogField::new(
-                                    "error_msg",
-                                    e.to_string(),
-                                ));
-                            });
-                            ret
+                            #report_err
                         }
-                        Ok(_) => {
+                        Ok(value) => {
                             span.set_tag(|| Tag::new("error", false));
-                            ret
+                            #log_value
                         }
                     }
                 }
-                None => ret,
             }
         }
-    } else {
-        quote! { #func_block }
-    };
-
-    let res = quote! {
-        #[allow(unused_variables, clippy::type_complexity)]
-        #func_vis #func_async fn #func_name #func_generics(#func_inputs) #func_output #where_clause {
-            use muta_apm::rustracing_jaeger::span::SpanContext;
-            use muta_apm::rustracing::tag::Tag;
-            use muta_apm::rustracing::log::LogField;
-
-            let mut span_tags: Vec<Tag> = Vec::new();
-            #(#span_tag_stmts)*
-
-            let mut span_logs: Vec<LogField> = Vec::new();
-            #(#span_log_stmts)*
-
-            let mut span = if let Some(parent_ctx) = ctx.get::<Option<SpanContext>>("parent_span_ctx") {
-                if parent_ctx.is_some() {
-                    muta_apm::MUTA_TRACER.child_of_span(#trace_name, parent_ctx.clone().unwrap(), span_tags)
-                } else {
-                    muta_apm::MUTA_TRACER.span(#trace_name, span_tags)
+        RetKind::Option if ret_format.is_some() => quote! {
+            if let Some(span) = span.as_mut() {
+                span.set_tag(|| Tag::new("return.is_some", ret.is_some()));
+                if let Some(value) = ret.as_ref() {
+                    #log_value
                 }
-            } else {
-                muta_apm::MUTA_TRACER.span(#trace_name, span_tags)
-            };
+            }
+        },
+        RetKind::Unknown => {
+            let report_err = report_err(err_record);
+            quote! {
+                use muta_apm::error::{NotResultErr as _, ResultErr as _, ResultWrap};
 
-            let ctx = match span.as_mut() {
-                Some(span) => {
-                    span.log(|log| {
-                        for span_log in span_logs.into_iter() {
-                            log.field(span_log);
+                match (&ResultWrap(&ret)).result_err() {
+                    Some(Err(e)) => {
+                        span.keep();
+                        if let Some(span) = span.as_mut() {
+                            span.set_tag(|| Tag::new("error", true));
+                            #report_err
                         }
-                    });
-                    ctx.with_value("parent_span_ctx", span.context().cloned())
-                },
-                None => ctx,
-            };
+                    }
+                    Some(Ok(())) => {
+                        if let Some(span) = span.as_mut() {
+                            span.set_tag(|| Tag::new("error", false));
+                        }
+                    }
+                    None => (),
+                }
+                if let Some(span) = span.as_mut() {
+                    let value = &ret;
+                    #log_value
+                }
+            }
+        }
+        RetKind::Plain if ret_format.is_some() => quote! {
+            if let Some(span) = span.as_mut() {
+                let value = &ret;
+                #log_value
+            }
+        },
+        _ => quote! {},
+    }
+}
 
-            #func_block_report_err
+// Statements run on the error `e` of a sampled span.
+fn report_err(err_record: ErrRecord) -> proc_macro2::TokenStream {
+    let kind_tag = if err_record.kind {
+        quote! {
+            use muta_apm::error::{CustomErrorKind as _, ErrorKindWrap, TypeErrorKind as _};
+            let kind = (&ErrorKindWrap(e)).error_kind().into_owned();
+            span.set_tag(|| Tag::new("error.kind", kind));
         }
+    } else {
+        quote! {}
     };
-    res.into()
-}
 
-fn is_return_result(ret_type: &ReturnType) -> bool {
-    match ret_type {
-        ReturnType::Default => false,
-
-        ReturnType::Type(_, ty) => match ty.as_ref() {
-            Type::Path(path) => path
-                .path
-                .segments
-                .last()
-                .expect("at least one path segment")
-                .ident
-                .to_string()
-                .contains("Result"),
-            _ => false,
-        },
+    let mut log_fields = Vec::new();
+    if err_record.msg {
+        log_fields.push(quote! { log.field(LogField::new("error_msg", e.to_string())); });
+    }
+    if err_record.source {
+        log_fields.push(quote! {
+            use muta_apm::error::{DerefErrorSources as _, ErrorSources as _, ErrorWrap};
+            log.field(LogField::new("error.source", (&ErrorWrap(e)).error_sources()));
+        });
+    }
+    if err_record.debug {
+        log_fields.push(quote! { log.field(LogField::new("error.debug", format!("{:?}", e))); });
+    }
+
+    if log_fields.is_empty() {
+        return kind_tag;
+    }
+
+    quote! {
+        #kind_tag
+        span.log(|log| {
+            #(#log_fields)*
+        });
     }
 }
 
-fn is_fut_ret_result(intput: &PathArguments, fut_ret: &mut PinBoxFutRet) -> bool {
-    match intput {
-        PathArguments::AngleBracketed(angle_arg) => {
-            match angle_arg.args.first().expect("future output") {
-                GenericArgument::Binding(binding) => match &binding.ty {
-                    Type::Path(path) => {
-                        fut_ret.set_ret_type(quote! { #path });
-                        path.path
-                            .segments
-                            .last()
-                            .unwrap()
-                            .ident
-                            .to_string()
-                            .contains("Result")
-                    }
-                    _ => false,
-                },
-                _ => false,
+// Whether the type holds a `&mut` borrow, like `Result<&mut T, E>`.
+fn has_mut_ref(ty: &Type) -> bool {
+    fn tokens_have_mut_ref(tokens: proc_macro2::TokenStream) -> bool {
+        // After `&`, and its lifetime if any.
+        let mut after_ref = false;
+        let mut after_quote = false;
+        for token in tokens.into_iter() {
+            match token {
+                TokenTree::Group(group) if tokens_have_mut_ref(group.stream()) => return true,
+                TokenTree::Punct(punct) if punct.as_char() == '&' => {
+                    after_ref = true;
+                    continue;
+                }
+                TokenTree::Punct(punct) if after_ref && punct.as_char() == '\'' => {
+                    after_quote = true;
+                    continue;
+                }
+                TokenTree::Ident(ident) if after_ref && ident == "mut" => return true,
+                TokenTree::Ident(_) if after_quote => {
+                    after_quote = false;
+                    continue;
+                }
+                _ => {}
             }
+            after_ref = false;
+            after_quote = false;
         }
+        false
+    }
+
+    tokens_have_mut_ref(quote! { #ty })
+}
+
+// `Result` and aliases named like `ProtocolResult`, `ResultSet` is not
+// a result.
+fn is_result_type(ty: &Type) -> bool {
+    match ty {
+        Type::Path(path) => path
+            .path
+            .segments
+            .last()
+            .map(|seg| seg.ident.to_string().ends_with("Result"))
+            .unwrap_or(false),
+        Type::Group(group) => is_result_type(&group.elem),
+        Type::Paren(paren) => is_result_type(&paren.elem),
         _ => false,
     }
 }
@@ -0,0 +1,87 @@
+use proc_macro2::TokenStream;
+use quote::{quote, quote_spanned};
+use syn::{FnArg, Ident, Pat, PatType, Signature};
+
+use crate::attr_parse::{FieldFormat, TracingAttrs};
+use crate::expand::DEFAULT_MAX_LEN;
+
+/// Tag statements recording the function arguments selected by `fields(..)`
+/// and `skip(..)`. The receiver and the context argument are never recorded.
+pub fn field_tags(
+    sig: &Signature,
+    ctx_ident: Option<&Ident>,
+    tracing_attrs: &TracingAttrs,
+) -> syn::Result<Vec<TokenStream>> {
+    let args = sig
+        .inputs
+        .iter()
+        .filter_map(|arg| match arg {
+            FnArg::Typed(PatType { pat, .. }) => match pat.as_ref() {
+                Pat::Ident(pat_ident) => Some(pat_ident.ident.clone()),
+                _ => None,
+            },
+            FnArg::Receiver(_) => None,
+        })
+        .filter(|ident| Some(ident) != ctx_ident)
+        .collect::<Vec<_>>();
+
+    for ident in tracing_attrs
+        .skip
+        .iter()
+        .chain(tracing_attrs.fields.iter().map(|(ident, _)| ident))
+    {
+        if !args.contains(ident) {
+            return Err(syn::Error::new(
+                ident.span(),
+                format!(
+                    "`{}` is not a recordable argument of `{}`",
+                    ident, sig.ident
+                ),
+            ));
+        }
+    }
+
+    let max_len = tracing_attrs.fields_len.unwrap_or(DEFAULT_MAX_LEN);
+    let stmts = args
+        .iter()
+        .filter(|ident| !tracing_attrs.skip.contains(ident))
+        .filter_map(|ident| {
+            let format = tracing_attrs
+                .fields
+                .iter()
+                .find(|(field, _)| field == ident)
+                .map(|(_, format)| *format);
+
+            match format {
+                Some(format) => Some(field_tag(ident, Some(format), max_len)),
+                None if tracing_attrs.is_fields_all() => Some(field_tag(ident, None, max_len)),
+                None => None,
+            }
+        })
+        .collect();
+
+    Ok(stmts)
+}
+
+// `None` for an argument recorded by `fields(all)`, which falls back to
+// `Debug` if it's not `Display`. The value is spanned on the argument, so
+// that a type missing the trait is reported there.
+fn field_tag(ident: &Ident, format: Option<FieldFormat>, max_len: usize) -> TokenStream {
+    let key = ident.to_string();
+    let value = match format {
+        Some(FieldFormat::Value) => {
+            quote_spanned! { ident.span()=> (&TagValueWrap(&#ident)).tag_value() }
+        }
+        Some(FieldFormat::Display) => {
+            quote_spanned! { ident.span()=> TagValue::from(format!("{}", #ident)) }
+        }
+        Some(FieldFormat::Debug) => {
+            quote_spanned! { ident.span()=> TagValue::from(format!("{:?}", #ident)) }
+        }
+        None => quote_spanned! { ident.span()=> (&&&FieldValueWrap(&#ident)).field_value() },
+    };
+
+    quote! {
+        span.set_tag(|| Tag::new(#key, muta_apm::tag::truncate_tag_value(#value, #max_len)));
+    }
+}
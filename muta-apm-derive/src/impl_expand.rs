@@ -0,0 +1,97 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::parse::Parser;
+use syn::{
+    parse_macro_input, parse_quote, Attribute, Ident, ImplItem, ImplItemMethod, ItemImpl, LitStr,
+};
+
+use crate::attr_parse::{parse_impl_attrs, ImplAttrs};
+use crate::context::find_ctx;
+
+static TRACING_SPAN: &str = "tracing_span";
+
+pub fn impl_expand(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut item_impl = parse_macro_input!(item as ItemImpl);
+    let impl_attrs = match parse_impl_attrs.parse(attr) {
+        Ok(attrs) => attrs,
+        Err(e) => return e.to_compile_error().into(),
+    };
+
+    if let Err(e) = check_skip(&item_impl, &impl_attrs) {
+        return e.to_compile_error().into();
+    }
+
+    for impl_item in item_impl.items.iter_mut() {
+        if let ImplItem::Method(method) = impl_item {
+            instrument_method(method, &impl_attrs);
+        }
+    }
+
+    let res = quote! { #item_impl };
+    res.into()
+}
+
+// Every skipped name must be a method of the impl, a typo would instrument
+// the method silently.
+fn check_skip(item_impl: &ItemImpl, impl_attrs: &ImplAttrs) -> syn::Result<()> {
+    let is_method = |ident: &Ident| {
+        item_impl.items.iter().any(|impl_item| match impl_item {
+            ImplItem::Method(method) => method.sig.ident == *ident,
+            _ => false,
+        })
+    };
+
+    match impl_attrs.skip.iter().find(|ident| !is_method(ident)) {
+        Some(ident) => Err(syn::Error::new(
+            ident.span(),
+            format!("`{}` is not a method of this impl", ident),
+        )),
+        None => Ok(()),
+    }
+}
+
+// Methods already annotated with `#[tracing_span]` keep their own options,
+// and only inherit the shared kind if they don't set one. Other methods are
+// instrumented if they take a context and are not skipped.
+fn instrument_method(method: &mut ImplItemMethod, impl_attrs: &ImplAttrs) {
+    let kind = impl_attrs.kind.as_ref();
+
+    if let Some(attr) = method.attrs.iter_mut().find(|attr| is_tracing_span(attr)) {
+        if let Some(kind) = kind {
+            let args = attr.parse_args::<TokenStream2>().unwrap_or_default();
+            attr.tokens = with_kind(kind, args);
+        }
+        return;
+    }
+
+    let skipped = impl_attrs.skip.contains(&method.sig.ident);
+    if skipped || find_ctx(&method.sig, None).is_err() {
+        return;
+    }
+
+    let args = match kind {
+        Some(kind) => with_kind(kind, quote! {}),
+        None => quote! {},
+    };
+    method
+        .attrs
+        .push(parse_quote! { #[muta_apm::derive::tracing_span #args] });
+}
+
+// Later options win, so the method's own `kind` overrides the shared one.
+fn with_kind(kind: &LitStr, args: TokenStream2) -> TokenStream2 {
+    if args.is_empty() {
+        quote! { (kind = #kind) }
+    } else {
+        quote! { (kind = #kind, #args) }
+    }
+}
+
+fn is_tracing_span(attr: &Attribute) -> bool {
+    attr.path
+        .segments
+        .last()
+        .map(|seg| seg.ident == TRACING_SPAN)
+        .unwrap_or(false)
+}
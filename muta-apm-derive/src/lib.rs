@@ -3,15 +3,46 @@
 #![allow(clippy::cmp_owned)]
 
 mod attr_parse;
+mod context;
 mod expand;
+mod fields;
+mod impl_expand;
+mod name;
+mod span_macro;
 
 extern crate proc_macro;
 
 use proc_macro::TokenStream;
 
 use crate::expand::func_expand;
+use crate::impl_expand::impl_expand;
+use crate::span_macro::span_expand;
 
 #[proc_macro_attribute]
 pub fn tracing_span(attr: TokenStream, func: TokenStream) -> TokenStream {
     func_expand(attr, func)
 }
+
+/// Instrument every method of an impl block taking a `creep::Context` with
+/// `#[tracing_span]`, e.g. `#[tracing_impl(kind = "consensus",
+/// skip(get_block))]`. Methods annotated with `#[tracing_span]` keep their own
+/// options.
+#[proc_macro_attribute]
+pub fn tracing_impl(attr: TokenStream, item: TokenStream) -> TokenStream {
+    impl_expand(attr, item)
+}
+
+/// Instrument a block or an `async` block with a child span of `ctx`, e.g.
+/// `apm_span!(ctx, "storage.commit", tags(height = height), { .. })`. Takes
+/// the options of `#[tracing_span]` except those tied to a function. If `ctx`
+/// is a variable, it's shadowed inside the block by the child context, still
+/// a `&Context` if `ctx` is one, owned by an `async` block. If it's a place
+/// like `req.ctx`, the child context is written in it while the block runs.
+/// `?` and `return` leave the block, like in an `async` block, also with
+/// the `disable-tracing` feature.
+/// An `Err` value marks the span as error, the type of the value must be
+/// known inside the block, e.g. `Ok::<_, ProtocolError>(..)`.
+#[proc_macro]
+pub fn apm_span(input: TokenStream) -> TokenStream {
+    span_expand(input)
+}
@@ -0,0 +1,101 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{FnArg, Ident, LitStr, Pat, PatType, Signature};
+
+use crate::attr_parse::TracingAttrs;
+
+/// The name of the span, `kind.name` as a literal, or if `name` holds
+/// `{arg}` placeholders, a call formatting it from the function arguments at
+/// runtime, e.g. `name = "exec_{service}.{method}"`.
+pub fn span_name(sig: &Signature, tracing_attrs: &TracingAttrs) -> syn::Result<TokenStream> {
+    let kind = &tracing_attrs.kind;
+    let name = match tracing_attrs.tracing_name.as_ref() {
+        Some(name) => name,
+        None => {
+            let trace_name = format!("{}.{}", kind, sig.ident);
+            return Ok(quote! { #trace_name });
+        }
+    };
+
+    let args = placeholders(name)?;
+    if args.is_empty() {
+        let trace_name = format!(
+            "{}.{}",
+            kind,
+            name.value().replace("{{", "{").replace("}}", "}")
+        );
+        return Ok(quote! { #trace_name });
+    }
+
+    for arg in args.iter() {
+        if !is_arg(sig, arg) {
+            return Err(syn::Error::new_spanned(
+                name,
+                format!("`{}` is not an argument of `{}`", arg, sig.ident),
+            ));
+        }
+    }
+
+    let template = format!(
+        "{}.{}",
+        kind.replace('{', "{{").replace('}', "}}"),
+        name.value()
+    );
+    Ok(quote! {
+        muta_apm::MUTA_TRACER.span_name(#template, || format!(#template, #(#args = #args),*))
+    })
+}
+
+// The distinct arguments referenced by `{arg}` or `{arg:?}`, `{{` and `}}`
+// are escaped braces.
+fn placeholders(name: &LitStr) -> syn::Result<Vec<Ident>> {
+    let value = name.value();
+    let mut args = Vec::new();
+    let mut chars = value.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+            }
+            '{' => {
+                let mut placeholder = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => placeholder.push(c),
+                        None => return Err(syn::Error::new_spanned(name, "unclosed `{` in name")),
+                    }
+                }
+
+                let arg = placeholder.split(':').next().unwrap_or_default().trim();
+                let arg = syn::parse_str::<Ident>(arg).map_err(|_| {
+                    syn::Error::new_spanned(
+                        name,
+                        format!("expected an argument name in `{{{}}}`", placeholder),
+                    )
+                })?;
+                if !args.contains(&arg) {
+                    args.push(arg);
+                }
+            }
+            '}' => return Err(syn::Error::new_spanned(name, "unmatched `}` in name")),
+            _ => (),
+        }
+    }
+
+    Ok(args)
+}
+
+fn is_arg(sig: &Signature, ident: &Ident) -> bool {
+    sig.inputs.iter().any(|arg| match arg {
+        FnArg::Typed(PatType { pat, .. }) => match pat.as_ref() {
+            Pat::Ident(pat_ident) => pat_ident.ident == *ident,
+            _ => false,
+        },
+        FnArg::Receiver(_) => false,
+    })
+}
@@ -0,0 +1,244 @@
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::ext::IdentExt;
+use syn::parse::{Parse, ParseStream, Parser};
+use syn::{
+    parenthesized, parse_macro_input, token, Block, Expr, ExprAsync, Ident, Lit, LitStr, Token,
+};
+
+use crate::attr_parse::{parse_attrs, TracingAttrs};
+use crate::context::check_place;
+use crate::expand::{report_ret, ret_kind, span_imports, start_span, RetKind, DEFAULT_MAX_LEN};
+
+// Options of `#[tracing_span]` which only make sense on a function, the kind
+// and the name of the span are taken from the span name argument.
+static FN_OPTIONS: &[&str] = &["kind", "name", "ctx", "fields", "skip"];
+
+/// `apm_span!(ctx, "kind.name", options.., { .. })`, the options are the ones
+/// of `#[tracing_span]` not tied to a function signature.
+struct SpanArgs {
+    ctx:   Expr,
+    name:  LitStr,
+    attrs: TracingAttrs,
+    body:  SpanBody,
+}
+
+enum SpanBody {
+    Block(Block),
+    Async(ExprAsync),
+}
+
+impl Parse for SpanArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let ctx = input.parse::<Expr>()?;
+        input.parse::<Token![,]>()?;
+
+        let name = input.parse::<LitStr>()?;
+        let kind = match name.value().find('.') {
+            Some(pos) if pos > 0 => name.value()[..pos].to_string(),
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    name,
+                    "expected a `kind.name` span name",
+                ))
+            }
+        };
+        input.parse::<Token![,]>()?;
+
+        let mut options = proc_macro2::TokenStream::new();
+        while !(input.peek(token::Brace) || input.peek(Token![async])) {
+            let ident = input.call(Ident::parse_any)?;
+            if FN_OPTIONS.contains(&ident.to_string().as_str()) {
+                return Err(syn::Error::new(
+                    ident.span(),
+                    format!("`{}` is not supported by `apm_span!`", ident),
+                ));
+            }
+
+            if input.peek(Token![=]) {
+                let eq = input.parse::<Token![=]>()?;
+                let lit = input.parse::<Lit>()?;
+                options.extend(quote! { #ident #eq #lit });
+            } else if input.peek(token::Paren) {
+                let content;
+                parenthesized!(content in input);
+                let list = content.parse::<proc_macro2::TokenStream>()?;
+                options.extend(quote! { #ident(#list) });
+            } else {
+                options.extend(quote! { #ident });
+            }
+
+            let comma = input.parse::<Token![,]>()?;
+            options.extend(quote! { #comma });
+        }
+
+        let mut attrs = parse_attrs.parse2(options)?;
+        attrs.kind = kind;
+
+        let body = if input.peek(Token![async]) {
+            SpanBody::Async(input.parse()?)
+        } else {
+            SpanBody::Block(input.parse()?)
+        };
+        input.parse::<Option<Token![,]>>()?;
+
+        Ok(SpanArgs {
+            ctx,
+            name,
+            attrs,
+            body,
+        })
+    }
+}
+
+pub fn span_expand(input: TokenStream) -> TokenStream {
+    let SpanArgs {
+        ctx,
+        name,
+        attrs,
+        body,
+    } = parse_macro_input!(input as SpanArgs);
+
+    // A context variable is shadowed by the child context, a place like
+    // `self.ctx` is written in while the block runs.
+    let ctx_var = match strip_ref(&ctx) {
+        Expr::Path(path) if path.qself.is_none() && path.path.get_ident().is_some() => {
+            Some(path.path.clone())
+        }
+        place => {
+            if let Err(e) = check_place(place) {
+                return e.to_compile_error().into();
+            }
+            // The returned future can't borrow the place to restore it.
+            if let SpanBody::Async(_) = body {
+                return syn::Error::new_spanned(
+                    place,
+                    "`apm_span!` with an `async` block takes a context variable",
+                )
+                .to_compile_error()
+                .into();
+            }
+            None
+        }
+    };
+
+    // The block runs in a closure, so that `?` and `return` leave the block
+    // and its value is still reported. The child context is passed like the
+    // parent one, except to an `async` block, which owns it.
+    if cfg!(feature = "disable-tracing") {
+        let res = match (body, ctx_var) {
+            (SpanBody::Block(block), Some(ctx)) => quote! {
+                {
+                    #[allow(unused_variables)]
+                    let __muta_apm_child_ctx = muta_apm::guard::SpanCtx::parent(&#ctx).clone();
+                    #[allow(unused_variables)]
+                    let #ctx = muta_apm::guard::SpanCtx::child(&#ctx, &__muta_apm_child_ctx);
+                    (|| #block)()
+                }
+            },
+            (SpanBody::Block(block), None) => quote! { (|| #block)() },
+            (SpanBody::Async(async_block), Some(ctx)) => quote! {
+                {
+                    #[allow(unused_variables)]
+                    let #ctx = muta_apm::guard::SpanCtx::parent(&#ctx).clone();
+                    #async_block
+                }
+            },
+            (SpanBody::Async(async_block), None) => quote! { #async_block },
+        };
+        return res.into();
+    }
+
+    let trace_name = quote! { #name };
+    let parent_ctx = quote! { (#ctx) };
+    let span_imports = span_imports();
+    let start_span = start_span(&trace_name, &parent_ctx, &attrs, &[]);
+
+    let (inject_child_ctx, restore_parent_ctx) = match (&body, ctx_var) {
+        (SpanBody::Block(_), Some(ctx)) => {
+            let inject_child_ctx = quote! {
+                #[allow(unused_variables)]
+                let __muta_apm_child_ctx = __muta_apm_span.child_or_clone(&#ctx);
+                #[allow(unused_variables)]
+                let #ctx = muta_apm::guard::SpanCtx::child(&#ctx, &__muta_apm_child_ctx);
+            };
+            (inject_child_ctx, quote! {})
+        }
+        (SpanBody::Async(_), Some(ctx)) => {
+            let inject_child_ctx = quote! {
+                #[allow(unused_variables)]
+                let #ctx = __muta_apm_span.child_or_clone(&#ctx);
+            };
+            (inject_child_ctx, quote! {})
+        }
+        (_, None) => {
+            let place = strip_ref(&ctx);
+            let inject_child_ctx = quote! {
+                let __muta_apm_parent_keys = __muta_apm_span.enter_ctx(&mut #place);
+            };
+            let restore_parent_ctx = quote! {
+                __muta_apm_parent_keys.restore(&mut #place);
+            };
+            (inject_child_ctx, restore_parent_ctx)
+        }
+    };
+
+    let ret_kind = match attrs.err {
+        Some(_) => ret_kind(None, attrs.err),
+        None => RetKind::Unknown,
+    };
+    let max_len = attrs.fields_len.unwrap_or(DEFAULT_MAX_LEN);
+    let report_ret = report_ret(ret_kind, attrs.ret_format, attrs.err_record, max_len);
+
+    // The guard is bound to a name the block won't refer to, it may use its
+    // own `span`.
+    let report = quote! {
+        #[allow(unused_imports, unused_variables)]
+        {
+            #span_imports
+            let span = &mut __muta_apm_span;
+            #report_ret
+        }
+    };
+    let body_report = match body {
+        SpanBody::Block(block) => quote! {
+            let __muta_apm_entered = __muta_apm_span.enter();
+            let ret = (|| #block)();
+            #restore_parent_ctx
+            #report
+            ret
+        },
+        SpanBody::Async(async_block) => quote! {
+            let fut = #async_block;
+            async move {
+                let ret = muta_apm::panic::CatchPanic::new(&mut __muta_apm_span, fut).await;
+                #report
+                ret
+            }
+        },
+    };
+
+    let res = quote! {
+        {
+            #[allow(unused_imports)]
+            let mut __muta_apm_span = {
+                #span_imports
+                #start_span
+                span
+            };
+            #inject_child_ctx
+
+            #body_report
+        }
+    };
+    res.into()
+}
+
+// `&ctx` and `&mut ctx` name the same context as `ctx`.
+fn strip_ref(ctx: &Expr) -> &Expr {
+    match ctx {
+        Expr::Reference(reference) => strip_ref(&reference.expr),
+        Expr::Paren(paren) => strip_ref(&paren.expr),
+        _ => ctx,
+    }
+}
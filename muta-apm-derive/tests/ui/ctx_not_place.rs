@@ -0,0 +1,8 @@
+use muta_apm_derive::tracing_span;
+
+#[tracing_span(kind = "main", ctx = "make_ctx()")]
+fn multi(a: u64, b: u64) -> u64 {
+    a * b
+}
+
+fn main() {}
@@ -0,0 +1,14 @@
+use muta_apm_derive::tracing_span;
+
+struct Request {
+    ctx: (),
+}
+
+impl Request {
+    #[tracing_span(kind = "main", ctx = "self.ctx")]
+    fn handle(&mut self) -> impl std::future::Future<Output = u64> {
+        async { 1 }
+    }
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use muta_apm_derive::tracing_impl;
+
+struct Consensus;
+
+#[tracing_impl(kind = "consensus", name = "commit")]
+impl Consensus {
+    fn commit(&self, ctx: ()) {}
+}
+
+fn main() {}
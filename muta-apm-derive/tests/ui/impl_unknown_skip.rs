@@ -0,0 +1,10 @@
+use muta_apm_derive::tracing_impl;
+
+struct Consensus;
+
+#[tracing_impl(kind = "consensus", skip(get_blok))]
+impl Consensus {
+    fn get_block(&self, ctx: ()) {}
+}
+
+fn main() {}
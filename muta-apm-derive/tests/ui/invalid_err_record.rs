@@ -0,0 +1,8 @@
+use muta_apm_derive::tracing_span;
+
+#[tracing_span(kind = "main", err(msg, stack))]
+fn get_block(ctx: ()) -> Result<(), String> {
+    Ok(())
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+use muta_apm_derive::tracing_span;
+
+#[tracing_span(kind = "main", tags = "{'a': 'b',}")]
+fn multi(ctx: ()) {}
+
+fn main() {}
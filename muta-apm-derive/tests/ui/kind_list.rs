@@ -0,0 +1,6 @@
+use muta_apm_derive::tracing_span;
+
+#[tracing_span(kind(a = "main"))]
+fn multi(ctx: ()) {}
+
+fn main() {}
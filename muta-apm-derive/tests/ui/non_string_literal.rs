@@ -0,0 +1,6 @@
+use muta_apm_derive::tracing_span;
+
+#[tracing_span(kind = 1)]
+fn multi(ctx: ()) {}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use muta_apm_derive::apm_span;
+
+struct Request {
+    ctx: (),
+}
+
+fn main() {
+    let req = Request { ctx: () };
+    let _ = apm_span!(req.ctx, "storage.commit", async move { 1 });
+}
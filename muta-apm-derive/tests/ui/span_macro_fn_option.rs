@@ -0,0 +1,6 @@
+use muta_apm_derive::apm_span;
+
+fn main() {
+    let ctx = ();
+    apm_span!(ctx, "storage.commit", fields(height), { 1 });
+}
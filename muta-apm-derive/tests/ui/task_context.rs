@@ -0,0 +1,8 @@
+use muta_apm_derive::tracing_span;
+
+#[tracing_span(kind = "main")]
+fn poll_ready(cx: &mut std::task::Context<'_>) -> std::task::Poll<()> {
+    std::task::Poll::Ready(())
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+use muta_apm_derive::tracing_span;
+
+#[tracing_span(kind = "main", fields(height, ctx))]
+fn get_block(ctx: (), height: u64) {}
+
+fn main() {}
@@ -0,0 +1,6 @@
+use muta_apm_derive::tracing_span;
+
+#[tracing_span(kind = "service", name = "exec_{service}.{method}")]
+fn exec_service(ctx: (), service: &str) {}
+
+fn main() {}
@@ -4,7 +4,7 @@ use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use async_trait::async_trait;
 use bytes::Bytes;
 use creep::Context;
-use muta_apm_derive::tracing_span;
+use muta_apm_derive::{apm_span, tracing_impl, tracing_span};
 use muta_protocol::{ProtocolError, ProtocolErrorKind};
 
 const N: u64 = 41;
@@ -30,17 +30,18 @@ trait MockConsensus {
 
 struct Consensus {}
 
+#[tracing_impl(kind = "consensus")]
 #[async_trait]
 impl MockConsensus for Consensus {
-    #[tracing_span]
     async fn get_block(&self, ctx: Context, _height: u64) -> Option<Bytes> {
         Some(Bytes::new())
     }
 
-    #[tracing_span]
+    #[tracing_span(name = "commit_block")]
     async fn commit(&self, ctx: Context, info: Bytes) -> Result<(), Box<dyn Error + Send>> {
         use std::io;
         println!("{:?}", ctx);
+        muta_apm::apm_event!(ctx, "commit_start", info_len = info.len());
         Err(ProtocolError::from(ApmError::No).into())
     }
 }
@@ -58,7 +59,10 @@ async fn main() {
         k += 1;
     }
 
-    let res = rabin_miller(ctx.clone(), aa, m, k).await;
+    let res = apm_span!(ctx, "main.check_prime", tags(n = N), async move {
+        rabin_miller(ctx, aa, m, k).await
+    })
+    .await;
 
     println!("{} is prime number {:?}", N, res);
 
@@ -74,7 +78,7 @@ fn init_ctx() -> Context {
     Context::new()
 }
 
-#[tracing_span(kind = "main", name = "power_mod")]
+#[tracing_span(kind = "main", name = "power_mod", tags = "{'m': 'm'}", ret)]
 pub async fn power_mod(ctx: Context, mut a: u64, mut b: u64, m: u64) -> u64 {
     let mut res = 1u64;
     a %= m;
@@ -89,7 +93,7 @@ pub async fn power_mod(ctx: Context, mut a: u64, mut b: u64, m: u64) -> u64 {
     res
 }
 
-#[tracing_span(kind = "main")]
+#[tracing_span(kind = "main", fields(m, k))]
 async fn rabin_miller(ctx: Context, aa: Vec<u64>, m: u64, k: u64) -> bool {
     for a in aa.into_iter() {
         let mut x = power_mod(ctx.clone(), a, m, N).await;
@@ -108,7 +112,7 @@ async fn rabin_miller(ctx: Context, aa: Vec<u64>, m: u64, k: u64) -> bool {
     true
 }
 
-#[tracing_span(kind = "main", tags = "{'a': 'b', 'c': 'd'}", logs = "{'c': 'm + 3'}")]
+#[tracing_span(kind = "main", tags(a = b, c = "d"), logs(c = m + 3), sample_rate = 0.1)]
 fn multi(ctx: Context, mut a: u64, mut b: u64, m: u64) -> u64 {
     let mut res = 0u64;
     a %= m;
@@ -126,5 +130,8 @@ fn multi(ctx: Context, mut a: u64, mut b: u64, m: u64) -> u64 {
 #[tracing_span]
 fn report_err(ctx: Context) -> Result<(), String> {
     println!("{:?}", ctx);
-    Err(String::new())
+    match muta_apm::Correlation::from_context(&ctx) {
+        Some(correlation) => Err(format!("failed, trace {}", correlation)),
+        None => Err(String::new()),
+    }
 }
@@ -0,0 +1,89 @@
+use std::fmt;
+
+use rustracing_jaeger::span::{SpanContext, TraceId};
+
+use crate::guard::current_span;
+
+/// The trace id of the span of `ctx`, `None` if it holds no span.
+pub fn trace_id(ctx: &creep::Context) -> Option<TraceId> {
+    span_context(ctx).map(|span_ctx| span_ctx.state().trace_id())
+}
+
+/// The id of the span of `ctx`, `None` if it holds no span.
+pub fn span_id(ctx: &creep::Context) -> Option<u64> {
+    span_context(ctx).map(|span_ctx| span_ctx.state().span_id())
+}
+
+fn span_context(ctx: &creep::Context) -> Option<&SpanContext> {
+    match ctx.get::<Option<SpanContext>>("parent_span_ctx") {
+        Some(Some(span_ctx)) => Some(span_ctx),
+        _ => None,
+    }
+}
+
+/// The trace and span ids of a span, displayed as `trace_id:span_id` in hex
+/// to tag RPC error responses and log lines, e.g. `4bf92f3577b34da6:a3ce929d`.
+#[derive(Clone, Debug)]
+pub struct Correlation {
+    pub trace_id: TraceId,
+    pub span_id:  u64,
+}
+
+impl Correlation {
+    /// The ids of the span of `ctx`.
+    pub fn from_context(ctx: &creep::Context) -> Option<Self> {
+        span_context(ctx).map(Correlation::new)
+    }
+
+    /// The ids of the current span of the thread, for code not taking a
+    /// context.
+    pub fn current() -> Option<Self> {
+        current_span().map(|current| Correlation::new(&current.span_ctx))
+    }
+
+    fn new(span_ctx: &SpanContext) -> Self {
+        Correlation {
+            trace_id: span_ctx.state().trace_id(),
+            span_id:  span_ctx.state().span_id(),
+        }
+    }
+}
+
+impl fmt::Display for Correlation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{:x}", self.trace_id, self.span_id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rustracing_jaeger::span::TraceId;
+
+    use super::{span_id, trace_id, Correlation};
+    use crate::MutaTracer;
+
+    #[test]
+    fn test_correlation() {
+        let trace = TraceId {
+            high: 0,
+            low:  0x4bf9_2f35,
+        };
+        let trace_str = trace.to_string();
+        let state = MutaTracer::new_state(trace, 0xa3ce);
+        let ctx = MutaTracer::inject_span_state(creep::Context::new(), state);
+
+        assert_eq!(
+            trace_id(&ctx).map(|id| id.to_string()),
+            Some(trace_str.clone())
+        );
+        assert_eq!(span_id(&ctx), Some(0xa3ce));
+        assert_eq!(
+            Correlation::from_context(&ctx).unwrap().to_string(),
+            format!("{}:a3ce", trace_str)
+        );
+
+        let ctx = creep::Context::new();
+        assert!(trace_id(&ctx).is_none());
+        assert!(Correlation::from_context(&ctx).is_none());
+    }
+}
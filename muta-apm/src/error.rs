@@ -0,0 +1,274 @@
+use std::any::type_name;
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+use std::ops::Deref;
+
+/// The `error.kind` tag recorded by `#[tracing_span]` for an error, e.g. the
+/// kind of a `ProtocolError`. Defaults to the type name of the error, which
+/// is also recorded for errors not implementing this trait.
+pub trait ApmErrorKind {
+    fn apm_error_kind(&self) -> Cow<'_, str> {
+        Cow::Owned(short_type_name(type_name::<Self>()))
+    }
+}
+
+impl<E: ApmErrorKind + ?Sized> ApmErrorKind for &E {
+    fn apm_error_kind(&self) -> Cow<'_, str> {
+        (**self).apm_error_kind()
+    }
+}
+
+impl<E: ApmErrorKind + ?Sized> ApmErrorKind for Box<E> {
+    fn apm_error_kind(&self) -> Cow<'_, str> {
+        (**self).apm_error_kind()
+    }
+}
+
+/// The type name of an error without module paths, e.g. `ProtocolError` or
+/// `Box<dyn Error>`.
+pub fn error_kind<E: ?Sized>(_: &E) -> String {
+    short_type_name(type_name::<E>())
+}
+
+/// The `source()` chain of an error, outermost cause first, separated by
+/// `": "`. Empty if the error has no source.
+pub fn error_sources<E: Error + ?Sized>(err: &E) -> String {
+    let mut sources = Vec::new();
+    let mut source = err.source();
+
+    while let Some(err) = source {
+        sources.push(err.to_string());
+        source = err.source();
+    }
+
+    sources.join(": ")
+}
+
+fn short_type_name(name: &str) -> String {
+    let mut short = String::with_capacity(name.len());
+    let mut rest = name;
+
+    // Drop the path in front of each `::`, i.e. everything back to the last
+    // char which can't be part of a path.
+    while let Some(pos) = rest.find("::") {
+        short.push_str(&rest[..pos]);
+        let start = short
+            .rfind(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .map(|pos| pos + 1)
+            .unwrap_or(0);
+        short.truncate(start);
+        rest = &rest[pos + 2..];
+    }
+    short.push_str(rest);
+
+    short
+}
+
+// `#[tracing_span]` records the kind of `Err(e)` through
+// `(&ErrorKindWrap(e)).error_kind()`, which resolves to `CustomErrorKind` if
+// the error implements `ApmErrorKind`, and to `TypeErrorKind` otherwise.
+#[doc(hidden)]
+pub struct ErrorKindWrap<T>(pub T);
+
+#[doc(hidden)]
+pub trait CustomErrorKind {
+    fn error_kind(&self) -> Cow<'_, str>;
+}
+
+impl<E: ApmErrorKind + ?Sized> CustomErrorKind for ErrorKindWrap<&E> {
+    fn error_kind(&self) -> Cow<'_, str> {
+        self.0.apm_error_kind()
+    }
+}
+
+#[doc(hidden)]
+pub trait TypeErrorKind {
+    fn error_kind(&self) -> Cow<'_, str>;
+}
+
+impl<E: ?Sized> TypeErrorKind for &ErrorKindWrap<&E> {
+    fn error_kind(&self) -> Cow<'_, str> {
+        Cow::Owned(error_kind(self.0))
+    }
+}
+
+// `#[tracing_span]` records the source chain of `Err(e)` through
+// `(&ErrorWrap(e)).error_sources()`, which resolves to `ErrorSources` if the
+// error implements `Error`, and to `DerefErrorSources` for boxed errors
+// like `Box<dyn Error + Send>`.
+#[doc(hidden)]
+pub struct ErrorWrap<T>(pub T);
+
+#[doc(hidden)]
+pub trait ErrorSources {
+    fn error_sources(&self) -> String;
+}
+
+impl<E: Error> ErrorSources for ErrorWrap<&E> {
+    fn error_sources(&self) -> String {
+        error_sources(self.0)
+    }
+}
+
+#[doc(hidden)]
+pub trait DerefErrorSources {
+    fn error_sources(&self) -> String;
+}
+
+impl<T: Deref> DerefErrorSources for &ErrorWrap<&T>
+where
+    T::Target: Error,
+{
+    fn error_sources(&self) -> String {
+        error_sources(&**self.0)
+    }
+}
+
+// `apm_span!` doesn't know the type of its block, it finds out whether the
+// value is a `Result` through `(&ResultWrap(&ret)).result_err()`, which
+// resolves to `ResultErr` for results and to `NotResultErr` otherwise.
+#[doc(hidden)]
+pub struct ResultWrap<'a, T>(pub &'a T);
+
+#[doc(hidden)]
+pub trait ResultErr<'a> {
+    type Err;
+
+    fn result_err(&self) -> Option<Result<(), &'a Self::Err>>;
+}
+
+impl<'a, T, E> ResultErr<'a> for ResultWrap<'a, Result<T, E>> {
+    type Err = E;
+
+    fn result_err(&self) -> Option<Result<(), &'a E>> {
+        match self.0 {
+            Ok(_) => Some(Ok(())),
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+#[doc(hidden)]
+pub trait NotResultErr {
+    fn result_err(&self) -> Option<Result<(), &'static NoError>>;
+}
+
+impl<'a, T> NotResultErr for &ResultWrap<'a, T> {
+    fn result_err(&self) -> Option<Result<(), &'static NoError>> {
+        None
+    }
+}
+
+/// The error of values which are not a `Result`, never constructed.
+#[doc(hidden)]
+#[derive(Debug)]
+pub enum NoError {}
+
+impl fmt::Display for NoError {
+    fn fmt(&self, _: &mut fmt::Formatter) -> fmt::Result {
+        match *self {}
+    }
+}
+
+impl Error for NoError {}
+
+#[cfg(test)]
+mod test {
+    use std::borrow::Cow;
+    use std::error::Error;
+    use std::fmt;
+
+    use super::{error_kind, error_sources, short_type_name, ApmErrorKind};
+    use super::{CustomErrorKind as _, ErrorKindWrap, TypeErrorKind as _};
+    use super::{DerefErrorSources as _, ErrorSources as _, ErrorWrap};
+    use super::{NotResultErr as _, ResultErr as _, ResultWrap};
+
+    #[derive(Debug)]
+    struct MockError(&'static str, Option<Box<MockError>>);
+
+    impl fmt::Display for MockError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{}", self.0)
+        }
+    }
+
+    impl Error for MockError {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            self.1
+                .as_ref()
+                .map(|err| err.as_ref() as &(dyn Error + 'static))
+        }
+    }
+
+    #[test]
+    fn test_error_kind() {
+        assert_eq!(error_kind(&MockError("a", None)), "MockError");
+        assert_eq!(
+            short_type_name("alloc::boxed::Box<dyn core::error::Error + core::marker::Send>"),
+            "Box<dyn Error + Send>"
+        );
+        assert_eq!(
+            short_type_name("core::result::Result<u64, my_crate::Error>"),
+            "Result<u64, Error>"
+        );
+    }
+
+    #[derive(Debug)]
+    enum KindError {
+        Storage,
+    }
+
+    impl ApmErrorKind for KindError {
+        fn apm_error_kind(&self) -> Cow<'_, str> {
+            match self {
+                KindError::Storage => Cow::Borrowed("storage"),
+            }
+        }
+    }
+
+    impl ApmErrorKind for MockError {}
+
+    #[test]
+    fn test_apm_error_kind() {
+        assert_eq!(
+            (&ErrorKindWrap(&KindError::Storage)).error_kind(),
+            "storage"
+        );
+        assert_eq!(
+            (&ErrorKindWrap(&MockError("a", None))).error_kind(),
+            "MockError"
+        );
+        assert_eq!((&ErrorKindWrap(&"io")).error_kind(), "&str");
+
+        let boxed = Box::new(KindError::Storage);
+        assert_eq!((&ErrorKindWrap(&boxed)).error_kind(), "storage");
+    }
+
+    #[test]
+    fn test_error_sources() {
+        let err = MockError(
+            "commit",
+            Some(Box::new(MockError(
+                "storage",
+                Some(Box::new(MockError("io", None))),
+            ))),
+        );
+        assert_eq!(error_sources(&err), "storage: io");
+        assert_eq!((&ErrorWrap(&err)).error_sources(), "storage: io");
+
+        let boxed: Box<dyn Error + Send> = Box::new(err);
+        assert_eq!((&ErrorWrap(&boxed)).error_sources(), "storage: io");
+    }
+
+    #[test]
+    fn test_result_err() {
+        let ok: Result<u64, MockError> = Ok(1);
+        let err: Result<u64, MockError> = Err(MockError("commit", None));
+
+        assert!((&ResultWrap(&ok)).result_err().unwrap().is_ok());
+        let e = (&ResultWrap(&err)).result_err().unwrap().unwrap_err();
+        assert_eq!(e.to_string(), "commit");
+        assert!((&ResultWrap(&1u64)).result_err().is_none());
+    }
+}
@@ -0,0 +1,121 @@
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use parking_lot::Mutex;
+use rustracing::log::LogField;
+use rustracing_jaeger::span::Span;
+
+/// Add a log record to the span of a `creep::Context`, e.g.
+/// `apm_event!(ctx, "block_received", height = height, hash = hash)`. The
+/// values are recorded with `to_string()`, and only evaluated if the context
+/// holds a span.
+#[macro_export]
+macro_rules! apm_event {
+    ($ctx:expr, $name:expr $(, $key:ident = $value:expr)* $(,)?) => {
+        $crate::event::record_event(&$ctx, $name, || {
+            vec![$($crate::rustracing::log::LogField::new(
+                stringify!($key),
+                ($value).to_string(),
+            )),*]
+        })
+    };
+}
+
+/// Add a log record named `name` to the span of `ctx`, timestamped now. Does
+/// nothing if `ctx` holds no span, e.g. tracing is off or the span is not
+/// sampled.
+pub fn record_event<N, F>(ctx: &creep::Context, name: N, fields: F)
+where
+    N: Into<Cow<'static, str>>,
+    F: FnOnce() -> Vec<LogField>,
+{
+    let events = match ctx.get::<SpanEvents>("parent_span_events") {
+        Some(events) => events,
+        None => return,
+    };
+
+    let mut log_fields = vec![LogField::new("event", name)];
+    log_fields.extend(fields());
+    events.record(log_fields);
+}
+
+#[derive(Debug)]
+struct Event {
+    time:   SystemTime,
+    fields: Vec<LogField>,
+}
+
+/// The events recorded on the context of a span, logged on the span when it
+/// finishes. Events recorded after that are dropped.
+#[doc(hidden)]
+#[derive(Clone, Debug)]
+pub struct SpanEvents(Arc<Mutex<Option<Vec<Event>>>>);
+
+impl SpanEvents {
+    pub(crate) fn new() -> Self {
+        SpanEvents(Arc::new(Mutex::new(Some(Vec::new()))))
+    }
+
+    pub(crate) fn finish(&self, span: &mut Span) {
+        let events = self.0.lock().take().unwrap_or_default();
+
+        for event in events {
+            span.log(|log| {
+                log.time(event.time);
+                for field in event.fields {
+                    log.field(field);
+                }
+            });
+        }
+    }
+
+    pub(crate) fn record(&self, fields: Vec<LogField>) {
+        let event = Event {
+            time: SystemTime::now(),
+            fields,
+        };
+
+        if let Some(events) = self.0.lock().as_mut() {
+            events.push(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rustracing::log::LogField;
+
+    use super::{record_event, SpanEvents};
+
+    #[test]
+    fn test_record_event() {
+        let events = SpanEvents::new();
+        let ctx = creep::Context::new().with_value("parent_span_events", events.clone());
+
+        record_event(&ctx, "commit", || vec![LogField::new("height", "1")]);
+        crate::apm_event!(ctx, "commit", height = 2, round = 0);
+        record_event(&creep::Context::new(), "commit", || {
+            panic!("fields of a context without span")
+        });
+
+        let recorded = events.0.lock().take().unwrap();
+        let fields = recorded
+            .iter()
+            .map(|event| {
+                event
+                    .fields
+                    .iter()
+                    .map(|field| format!("{}={}", field.name(), field.value()))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields[0], vec!["event=commit", "height=1"]);
+        assert_eq!(fields[1], vec!["event=commit", "height=2", "round=0"]);
+
+        // Dropped once the span is finished.
+        record_event(&ctx, "commit", Vec::new);
+        assert!(events.0.lock().is_none());
+    }
+}
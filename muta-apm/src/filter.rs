@@ -0,0 +1,117 @@
+use std::collections::HashSet;
+
+/// Allow and deny lists checked before a span is built. The kind of a span is
+/// the part of its name before the first `.`, prefixes are matched against
+/// the whole `kind.name`. Deny lists always win, an empty allow list allows
+/// everything.
+#[derive(Clone, Debug, Default)]
+pub struct SpanFilter {
+    allow_kinds:    HashSet<String>,
+    deny_kinds:     HashSet<String>,
+    allow_prefixes: Vec<String>,
+    deny_prefixes:  Vec<String>,
+}
+
+impl SpanFilter {
+    pub fn new() -> Self {
+        SpanFilter::default()
+    }
+
+    pub fn set_allow_kinds<I, K>(&mut self, kinds: I)
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        self.allow_kinds = kinds.into_iter().map(Into::into).collect();
+    }
+
+    pub fn set_deny_kinds<I, K>(&mut self, kinds: I)
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        self.deny_kinds = kinds.into_iter().map(Into::into).collect();
+    }
+
+    pub fn set_allow_prefixes<I, P>(&mut self, prefixes: I)
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<String>,
+    {
+        self.allow_prefixes = prefixes.into_iter().map(Into::into).collect();
+    }
+
+    pub fn set_deny_prefixes<I, P>(&mut self, prefixes: I)
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<String>,
+    {
+        self.deny_prefixes = prefixes.into_iter().map(Into::into).collect();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow_kinds.is_empty()
+            && self.deny_kinds.is_empty()
+            && self.allow_prefixes.is_empty()
+            && self.deny_prefixes.is_empty()
+    }
+
+    pub fn is_allowed(&self, opt_name: &str) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+
+        let kind = opt_name.split('.').next().unwrap_or_default();
+        if self.deny_kinds.contains(kind) || has_prefix(&self.deny_prefixes, opt_name) {
+            return false;
+        }
+
+        if self.allow_kinds.is_empty() && self.allow_prefixes.is_empty() {
+            return true;
+        }
+
+        self.allow_kinds.contains(kind) || has_prefix(&self.allow_prefixes, opt_name)
+    }
+}
+
+fn has_prefix(prefixes: &[String], opt_name: &str) -> bool {
+    prefixes
+        .iter()
+        .any(|prefix| opt_name.starts_with(prefix.as_str()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::SpanFilter;
+
+    #[test]
+    fn test_empty_filter() {
+        let filter = SpanFilter::new();
+        assert!(filter.is_allowed("consensus.commit"));
+        assert!(filter.is_allowed(".report_err"));
+    }
+
+    #[test]
+    fn test_allow_list() {
+        let mut filter = SpanFilter::new();
+        filter.set_allow_kinds(vec!["consensus"]);
+        filter.set_allow_prefixes(vec!["mempool.check"]);
+
+        assert!(filter.is_allowed("consensus.commit"));
+        assert!(filter.is_allowed("mempool.check_tx"));
+        assert!(!filter.is_allowed("mempool.flush"));
+        assert!(!filter.is_allowed("storage.insert"));
+    }
+
+    #[test]
+    fn test_deny_wins() {
+        let mut filter = SpanFilter::new();
+        filter.set_allow_kinds(vec!["consensus"]);
+        filter.set_deny_prefixes(vec!["consensus.get_block"]);
+        filter.set_deny_kinds(vec!["storage"]);
+
+        assert!(filter.is_allowed("consensus.commit"));
+        assert!(!filter.is_allowed("consensus.get_block"));
+        assert!(!filter.is_allowed("storage.insert"));
+    }
+}
@@ -0,0 +1,447 @@
+use std::cell::RefCell;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rustracing::log::LogField;
+use rustracing::tag::Tag;
+use rustracing_jaeger::span::{Span, SpanContext};
+
+use crate::event::SpanEvents;
+use crate::panic::last_panic;
+use crate::{DISCARD_TAG, MUTA_TRACER};
+
+thread_local! {
+    // The span of the instrumented code running on this thread, read by the
+    // log bridge and the tracing layer.
+    static CURRENT_SPAN: RefCell<Option<CurrentSpan>> = RefCell::new(None);
+}
+
+#[derive(Clone)]
+pub(crate) struct CurrentSpan {
+    pub(crate) span_ctx: SpanContext,
+    // `None` for the span of another process, injected in a context.
+    pub(crate) events:   Option<SpanEvents>,
+}
+
+pub(crate) fn current_span() -> Option<CurrentSpan> {
+    CURRENT_SPAN.with(|current| current.borrow().clone())
+}
+
+/// Restores the previous current span of the thread when dropped.
+pub struct Entered {
+    prev: Option<Option<CurrentSpan>>,
+}
+
+impl Entered {
+    // Without a span, the thread keeps the current span of the caller.
+    pub(crate) fn new(current: Option<CurrentSpan>) -> Self {
+        Entered {
+            prev: current
+                .map(|current| CURRENT_SPAN.with(|cell| cell.borrow_mut().replace(current))),
+        }
+    }
+
+    pub(crate) fn replaces_current(&self) -> bool {
+        self.prev.is_some()
+    }
+
+    // Exited before `next`, entered after it on the thread and replacing the
+    // current span: `next` restores what this one would have.
+    pub(crate) fn exit_before(mut self, next: &mut Entered) {
+        if let Some(prev) = self.prev.take() {
+            next.prev = Some(prev);
+        }
+    }
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        if let Some(prev) = self.prev.take() {
+            CURRENT_SPAN.with(|current| *current.borrow_mut() = prev);
+        }
+    }
+}
+
+/// Set if a span errored, or if one of its descendants is reported, so that
+/// the span is reported whatever its duration.
+#[doc(hidden)]
+#[derive(Clone, Debug, Default)]
+pub struct KeepFlag(Arc<AtomicBool>);
+
+impl KeepFlag {
+    fn set(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    fn is_set(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// The span keys of a context before `SpanGuard::enter_ctx` wrote the child
+/// context in it.
+#[doc(hidden)]
+#[derive(Default)]
+pub struct ParentKeys {
+    written:   bool,
+    span_ctx:  Option<SpanContext>,
+    events:    Option<SpanEvents>,
+    keep:      Option<KeepFlag>,
+    unsampled: bool,
+}
+
+impl ParentKeys {
+    /// Set the span keys of `ctx` back to the parent ones. A context can't
+    /// remove a key, a key the parent didn't have is set to `()`, which reads
+    /// as missing.
+    pub fn restore(self, ctx: &mut creep::Context) {
+        if !self.written {
+            return;
+        }
+
+        let parent_ctx = ctx.with_value("parent_span_ctx", self.span_ctx);
+        let parent_ctx = match self.events {
+            Some(events) => parent_ctx.with_value("parent_span_events", events),
+            None => parent_ctx.with_value("parent_span_events", ()),
+        };
+        let parent_ctx = match self.keep {
+            Some(keep) => parent_ctx.with_value("parent_span_keep", keep),
+            None => parent_ctx.with_value("parent_span_keep", ()),
+        };
+        *ctx = parent_ctx.with_value("parent_span_unsampled", self.unsampled);
+    }
+}
+
+/// The context variable of an `apm_span!` block, a `Context`, `&Context` or
+/// `&mut Context`. The block is given the child context like the parent one,
+/// a `&Context` stays a `&Context`, a `&mut Context` can't be written in and
+/// is passed an owned child.
+#[doc(hidden)]
+pub trait SpanCtx<'a> {
+    type Child;
+
+    fn parent(ctx: &Self) -> &creep::Context;
+
+    fn child(ctx: &Self, child_ctx: &'a creep::Context) -> Self::Child;
+}
+
+impl<'a> SpanCtx<'a> for creep::Context {
+    type Child = creep::Context;
+
+    fn parent(ctx: &Self) -> &creep::Context {
+        ctx
+    }
+
+    fn child(_ctx: &Self, child_ctx: &'a creep::Context) -> Self::Child {
+        child_ctx.clone()
+    }
+}
+
+impl<'a, 'b> SpanCtx<'a> for &'b creep::Context {
+    type Child = &'a creep::Context;
+
+    fn parent(ctx: &Self) -> &creep::Context {
+        ctx
+    }
+
+    fn child(_ctx: &Self, child_ctx: &'a creep::Context) -> Self::Child {
+        child_ctx
+    }
+}
+
+impl<'a, 'b> SpanCtx<'a> for &'b mut creep::Context {
+    type Child = creep::Context;
+
+    fn parent(ctx: &Self) -> &creep::Context {
+        ctx
+    }
+
+    fn child(_ctx: &Self, child_ctx: &'a creep::Context) -> Self::Child {
+        child_ctx.clone()
+    }
+}
+
+/// The span of a `#[tracing_span]` function. Marks the span as error if the
+/// function panics, and discards it on finish if it's faster than the slow
+/// threshold, unless it's kept.
+#[doc(hidden)]
+pub struct SpanGuard {
+    span:           Option<Span>,
+    start:          Instant,
+    slow_threshold: Option<Duration>,
+    // Only set with a span.
+    keep:           Option<KeepFlag>,
+    parent_keep:    Option<KeepFlag>,
+    events:         Option<SpanEvents>,
+    // Don't report the panic of a caller if created during unwinding.
+    panicking:      bool,
+    panic_done:     bool,
+}
+
+impl SpanGuard {
+    /// `slow_threshold_ms` overrides the threshold set on `MUTA_TRACER`.
+    pub fn new(
+        span: Option<Span>,
+        parent_keep: Option<KeepFlag>,
+        slow_threshold_ms: Option<u64>,
+    ) -> Self {
+        let slow_threshold = match slow_threshold_ms {
+            Some(ms) => Some(Duration::from_millis(ms)),
+            None => MUTA_TRACER.slow_threshold(),
+        }
+        .filter(|_| span.is_some());
+        let keep = span.as_ref().map(|_| KeepFlag::default());
+        let events = span.as_ref().map(|_| SpanEvents::new());
+
+        SpanGuard {
+            span,
+            start: Instant::now(),
+            slow_threshold,
+            keep,
+            parent_keep,
+            events,
+            panicking: thread::panicking(),
+            panic_done: false,
+        }
+    }
+
+    /// Report the span whatever its duration, e.g. because it errored.
+    pub fn keep(&self) {
+        if let Some(keep) = self.keep.as_ref() {
+            keep.set();
+        }
+    }
+
+    /// The context passed to the function body, holding this span as parent.
+    /// Under a span which isn't sampled, descendants don't start spans.
+    pub fn child_ctx(&self, ctx: &creep::Context) -> Option<creep::Context> {
+        let span = self.span.as_ref()?;
+        let span_ctx = span.context().cloned();
+        let unsampled = span_ctx.is_none();
+        let mut child_ctx = ctx
+            .with_value("parent_span_ctx", span_ctx)
+            .with_value("parent_span_unsampled", unsampled);
+        if let Some(events) = self.events.as_ref() {
+            child_ctx = child_ctx.with_value("parent_span_events", events.clone());
+        }
+
+        match self.keep.as_ref() {
+            Some(keep) => Some(child_ctx.with_value("parent_span_keep", keep.clone())),
+            None => Some(child_ctx),
+        }
+    }
+
+    /// Write the child context in `ctx`, for a function taking it by
+    /// `&mut` or reading it from a place like `self.ctx`. The returned keys
+    /// restore the parent span once the function returns, other values set
+    /// on `ctx` by the function are kept.
+    pub fn enter_ctx(&self, ctx: &mut creep::Context) -> ParentKeys {
+        let child_ctx = match self.child_ctx(ctx) {
+            Some(child_ctx) => child_ctx,
+            None => return ParentKeys::default(),
+        };
+        let parent_keys = ParentKeys {
+            written:   true,
+            span_ctx:  ctx
+                .get::<Option<SpanContext>>("parent_span_ctx")
+                .cloned()
+                .and_then(|span_ctx| span_ctx),
+            events:    ctx.get::<SpanEvents>("parent_span_events").cloned(),
+            keep:      ctx.get::<KeepFlag>("parent_span_keep").cloned(),
+            unsampled: ctx.get::<bool>("parent_span_unsampled") == Some(&true),
+        };
+
+        *ctx = child_ctx;
+        parent_keys
+    }
+
+    /// The child context if the span is started, a clone of `ctx` otherwise.
+    pub fn child_or_clone(&self, ctx: &creep::Context) -> creep::Context {
+        self.child_ctx(ctx).unwrap_or_else(|| ctx.clone())
+    }
+
+    /// Make the span the current one of the thread until the returned value
+    /// is dropped. Code under a span which isn't started keeps the current
+    /// span of its caller.
+    pub fn enter(&self) -> Entered {
+        let current = self
+            .span
+            .as_ref()
+            .and_then(|span| span.context())
+            .map(|span_ctx| CurrentSpan {
+                span_ctx: span_ctx.clone(),
+                events:   self.events.clone(),
+            });
+
+        Entered::new(current)
+    }
+
+    pub(crate) fn report_panic(&mut self) {
+        if self.panic_done {
+            return;
+        }
+        self.panic_done = true;
+        self.keep();
+
+        let span = match self.span.as_mut() {
+            Some(span) => span,
+            None => return,
+        };
+        let (msg, location) = last_panic();
+
+        span.set_tag(|| Tag::new("error", true));
+        span.set_tag(|| Tag::new("error.kind", "panic"));
+        span.log(|log| {
+            log.field(LogField::new("error_msg", msg));
+            if let Some(location) = location {
+                log.field(LogField::new("panic.location", location));
+            }
+        });
+    }
+}
+
+impl Deref for SpanGuard {
+    type Target = Option<Span>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.span
+    }
+}
+
+impl DerefMut for SpanGuard {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.span
+    }
+}
+
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        if !self.panicking && thread::panicking() {
+            self.report_panic();
+        }
+
+        let span = match self.span.as_mut() {
+            Some(span) => span,
+            None => return,
+        };
+        let is_slow = match self.slow_threshold {
+            Some(threshold) => self.start.elapsed() >= threshold,
+            None => false,
+        };
+        let is_kept = is_slow || self.keep.as_ref().map(KeepFlag::is_set).unwrap_or(false);
+
+        if self.slow_threshold.is_some() && !is_kept {
+            span.set_tag(|| Tag::new(DISCARD_TAG, true));
+            return;
+        }
+
+        if let Some(events) = self.events.as_ref() {
+            events.finish(span);
+        }
+        // A reported span keeps its parent, so that it isn't sent without it.
+        if let Some(parent_keep) = self.parent_keep.as_ref() {
+            parent_keep.set();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rustracing::sampler::AllSampler;
+    use rustracing_jaeger::span::{FinishedSpan, SpanReceiver};
+    use rustracing_jaeger::Tracer;
+
+    use super::{KeepFlag, SpanGuard};
+    use crate::DISCARD_TAG;
+
+    fn span_guard(
+        parent_keep: &KeepFlag,
+        slow_threshold_ms: Option<u64>,
+    ) -> (SpanGuard, SpanReceiver) {
+        let (span_tx, span_rx) = crossbeam_channel::unbounded();
+        let tracer = Tracer::with_sender(AllSampler, span_tx);
+        let span = tracer.span("consensus.commit").start();
+
+        let guard = SpanGuard::new(Some(span), Some(parent_keep.clone()), slow_threshold_ms);
+        (guard, span_rx)
+    }
+
+    fn is_discarded(span: &FinishedSpan) -> bool {
+        span.tags().iter().any(|tag| tag.name() == DISCARD_TAG)
+    }
+
+    #[test]
+    fn test_fast_span_discarded() {
+        let parent_keep = KeepFlag::default();
+        let (guard, span_rx) = span_guard(&parent_keep, Some(60_000));
+        drop(guard);
+
+        assert!(is_discarded(&span_rx.try_recv().unwrap()));
+        assert!(!parent_keep.is_set());
+    }
+
+    #[test]
+    fn test_kept_span_keeps_parent() {
+        let parent_keep = KeepFlag::default();
+        let (guard, span_rx) = span_guard(&parent_keep, Some(60_000));
+        guard.keep();
+        drop(guard);
+
+        assert!(!is_discarded(&span_rx.try_recv().unwrap()));
+        assert!(parent_keep.is_set());
+    }
+
+    #[test]
+    fn test_slow_span_keeps_parent() {
+        let parent_keep = KeepFlag::default();
+        let (guard, span_rx) = span_guard(&parent_keep, Some(0));
+        drop(guard);
+
+        assert!(!is_discarded(&span_rx.try_recv().unwrap()));
+        assert!(parent_keep.is_set());
+    }
+
+    #[test]
+    fn test_span_without_threshold() {
+        let parent_keep = KeepFlag::default();
+        let (guard, span_rx) = span_guard(&parent_keep, None);
+        drop(guard);
+
+        assert!(!is_discarded(&span_rx.try_recv().unwrap()));
+        assert!(parent_keep.is_set());
+    }
+
+    #[test]
+    fn test_reported_child_keeps_span() {
+        let parent_keep = KeepFlag::default();
+        let (guard, span_rx) = span_guard(&parent_keep, Some(60_000));
+        let child_keep = guard.keep.clone().unwrap();
+        let (child, child_rx) = span_guard(&child_keep, None);
+
+        drop(child);
+        drop(guard);
+
+        assert!(!is_discarded(&child_rx.try_recv().unwrap()));
+        assert!(!is_discarded(&span_rx.try_recv().unwrap()));
+        assert!(parent_keep.is_set());
+    }
+
+    #[test]
+    fn test_kept_child_keeps_span() {
+        let parent_keep = KeepFlag::default();
+        let (guard, span_rx) = span_guard(&parent_keep, Some(60_000));
+        let child_keep = guard.keep.clone().unwrap();
+        let (child, child_rx) = span_guard(&child_keep, Some(60_000));
+
+        child.keep();
+        drop(child);
+        drop(guard);
+
+        assert!(!is_discarded(&child_rx.try_recv().unwrap()));
+        assert!(!is_discarded(&span_rx.try_recv().unwrap()));
+        assert!(parent_keep.is_set());
+    }
+}
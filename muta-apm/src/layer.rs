@@ -0,0 +1,319 @@
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::fmt;
+
+use parking_lot::Mutex;
+use rustracing::log::LogField;
+use rustracing::tag::Tag;
+use rustracing_jaeger::span::Span;
+use tracing_core::field::{Field, Visit};
+use tracing_core::span::{Attributes, Id, Record};
+use tracing_core::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::event::SpanEvents;
+use crate::guard::{current_span, CurrentSpan, Entered};
+use crate::MUTA_TRACER;
+
+thread_local! {
+    // The `tracing` spans entered on this thread, innermost last, with the
+    // current spans they replaced.
+    static ENTERED: RefCell<Vec<(Id, Entered)>> = RefCell::new(Vec::new());
+}
+
+/// A `tracing_subscriber::Layer` reporting `tracing` spans through
+/// `MUTA_TRACER`, named `target.name`, with their fields as tags and their
+/// events as logs.
+///
+/// A span without explicit parent is a child of the current span of the
+/// thread, e.g. the span of a `#[tracing_span]` call or of a context entered
+/// with `MutaTracer::enter_context`. While entered, it's the current span
+/// itself, `MutaTracer::current_context` passes it to `#[tracing_span]` calls.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MutaLayer;
+
+// The span started for a `tracing` span, kept in its extensions.
+struct LayerSpan {
+    // Extensions must be `Sync`.
+    span:    Mutex<Span>,
+    current: CurrentSpan,
+}
+
+impl<S> Layer<S> for MutaLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let parent = if attrs.is_root() {
+            None
+        } else if let Some(parent) = attrs.parent() {
+            layer_span(&ctx, parent, |layer_span| {
+                layer_span.current.span_ctx.clone()
+            })
+        } else {
+            current_span().map(|current| current.span_ctx)
+        };
+
+        let metadata = attrs.metadata();
+        let opt_name = format!("{}.{}", metadata.target(), metadata.name());
+        let mut tags = TagVisitor(Vec::new());
+        attrs.record(&mut tags);
+
+        let span = match parent {
+            Some(parent) => MUTA_TRACER.child_of_span(opt_name, parent, tags.0),
+            None => MUTA_TRACER.span(opt_name, tags.0),
+        };
+        let span = match span {
+            Some(span) => span,
+            None => return,
+        };
+        // Not sampled.
+        let span_ctx = match span.context() {
+            Some(span_ctx) => span_ctx.clone(),
+            None => return,
+        };
+
+        if let Some(span_ref) = ctx.span(id) {
+            span_ref.extensions_mut().insert(LayerSpan {
+                span:    Mutex::new(span),
+                current: CurrentSpan {
+                    span_ctx,
+                    events: Some(SpanEvents::new()),
+                },
+            });
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        layer_span(&ctx, id, |layer_span| {
+            let mut tags = TagVisitor(Vec::new());
+            values.record(&mut tags);
+
+            let mut span = layer_span.span.lock();
+            for tag in tags.0.into_iter() {
+                span.set_tag(|| tag);
+            }
+        });
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let events = if let Some(parent) = event.parent() {
+            layer_span(&ctx, parent, |layer_span| layer_span.current.events.clone())
+        } else if event.is_contextual() {
+            current_span().map(|current| current.events)
+        } else {
+            None
+        };
+        let events = match events {
+            Some(Some(events)) => events,
+            _ => return,
+        };
+
+        let metadata = event.metadata();
+        let mut fields = LogVisitor(vec![
+            LogField::new("level", metadata.level().to_string()),
+            LogField::new("target", metadata.target().to_owned()),
+        ]);
+        event.record(&mut fields);
+        events.record(fields.0);
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        let current = layer_span(&ctx, id, |layer_span| layer_span.current.clone());
+        ENTERED.with(|entered| {
+            entered
+                .borrow_mut()
+                .push((id.clone(), Entered::new(current)))
+        });
+    }
+
+    // Spans aren't always exited in the reverse order they're entered, e.g.
+    // the spans of two futures polled in turn.
+    fn on_exit(&self, id: &Id, _ctx: Context<'_, S>) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            let pos = match entered.iter().rposition(|(entered_id, _)| entered_id == id) {
+                Some(pos) => pos,
+                None => return,
+            };
+
+            // Restores the span it replaced, unless a span entered after it
+            // has replaced it in turn.
+            let (_, exited) = entered.remove(pos);
+            let next = entered[pos..]
+                .iter_mut()
+                .find(|(_, next)| next.replaces_current());
+            if let Some((_, next)) = next {
+                exited.exit_before(next);
+            }
+        });
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let span_ref = match ctx.span(&id) {
+            Some(span_ref) => span_ref,
+            None => return,
+        };
+        let layer_span = span_ref.extensions_mut().remove::<LayerSpan>();
+
+        // Finished and sent to the reporter once dropped.
+        if let Some(layer_span) = layer_span {
+            let mut span = layer_span.span.into_inner();
+            if let Some(events) = layer_span.current.events.as_ref() {
+                events.finish(&mut span);
+            }
+        }
+    }
+}
+
+fn layer_span<S, F, T>(ctx: &Context<'_, S>, id: &Id, f: F) -> Option<T>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    F: FnOnce(&LayerSpan) -> T,
+{
+    let span_ref = ctx.span(id)?;
+    let extensions = span_ref.extensions();
+    extensions.get::<LayerSpan>().map(f)
+}
+
+struct TagVisitor(Vec<Tag>);
+
+impl Visit for TagVisitor {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.push(Tag::new(field.name(), value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        let tag = match i64::try_from(value) {
+            Ok(value) => Tag::new(field.name(), value),
+            Err(_) => Tag::new(field.name(), value.to_string()),
+        };
+        self.0.push(tag);
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.push(Tag::new(field.name(), value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push(Tag::new(field.name(), value.to_owned()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.push(Tag::new(field.name(), format!("{:?}", value)));
+    }
+}
+
+struct LogVisitor(Vec<LogField>);
+
+impl Visit for LogVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push(LogField::new(field.name(), value.to_owned()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .push(LogField::new(field.name(), format!("{:?}", value)));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rustracing::tag::TagValue;
+    use tracing::{dispatcher, Dispatch, Span};
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::Registry;
+
+    use super::MutaLayer;
+    use crate::guard::current_span;
+    use crate::{recv_test_span, register_test_tracer};
+
+    fn layer_dispatch() -> Dispatch {
+        register_test_tracer();
+        Dispatch::new(Registry::default().with(MutaLayer))
+    }
+
+    fn current_span_id() -> Option<u64> {
+        current_span().map(|current| current.span_ctx.state().span_id())
+    }
+
+    fn layer_span_id(span: &Span) -> Option<u64> {
+        let _entered = span.enter();
+        current_span_id()
+    }
+
+    #[test]
+    fn test_nested_spans() {
+        dispatcher::with_default(&layer_dispatch(), || {
+            let outer = tracing::info_span!("outer");
+            let inner = tracing::info_span!("inner");
+            let outer_id = layer_span_id(&outer);
+            let inner_id = layer_span_id(&inner);
+            assert!(outer_id.is_some());
+            assert_ne!(outer_id, inner_id);
+
+            {
+                let _outer = outer.enter();
+                {
+                    let _inner = inner.enter();
+                    assert_eq!(current_span_id(), inner_id);
+                }
+                assert_eq!(current_span_id(), outer_id);
+            }
+            assert_eq!(current_span_id(), None);
+        });
+    }
+
+    #[test]
+    fn test_exit_out_of_order() {
+        dispatcher::with_default(&layer_dispatch(), || {
+            let first = tracing::info_span!("first");
+            let second = tracing::info_span!("second");
+            let second_id = layer_span_id(&second);
+
+            let first_entered = first.enter();
+            let second_entered = second.enter();
+
+            drop(first_entered);
+            assert_eq!(current_span_id(), second_id);
+            drop(second_entered);
+            assert_eq!(current_span_id(), None);
+
+            let first_entered = first.enter();
+            let second_entered = second.enter();
+
+            drop(second_entered);
+            drop(first_entered);
+            assert_eq!(current_span_id(), None);
+        });
+    }
+
+    #[test]
+    fn test_reported_span() {
+        dispatcher::with_default(&layer_dispatch(), || {
+            let span = tracing::info_span!("reported", height = 1u64);
+            span.in_scope(|| tracing::info!(round = 2u64, "commit"));
+        });
+
+        let span = recv_test_span("muta_apm::layer::test.reported");
+        let height = span.tags().iter().find(|tag| tag.name() == "height");
+        match height.map(|tag| tag.value()) {
+            Some(TagValue::Integer(height)) => assert_eq!(*height, 1),
+            _ => panic!("no height tag"),
+        }
+
+        assert_eq!(span.logs().len(), 1);
+        let fields = span.logs()[0]
+            .fields()
+            .iter()
+            .map(|field| format!("{}={}", field.name(), field.value()))
+            .collect::<Vec<_>>();
+        assert_eq!(fields, vec![
+            "level=INFO",
+            "target=muta_apm::layer::test",
+            "message=commit",
+            "round=2",
+        ]);
+    }
+}
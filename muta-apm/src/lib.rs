@@ -4,94 +4,368 @@ pub use muta_apm_derive as derive;
 pub use rustracing;
 pub use rustracing_jaeger;
 
+pub mod correlation;
+pub mod error;
+pub mod event;
+pub mod guard;
+#[cfg(feature = "tracing-layer")]
+pub mod layer;
+pub mod logger;
+pub mod panic;
+pub mod sample;
+pub mod tag;
+pub mod task;
+
+mod filter;
+mod name;
+mod reporter;
+
 use std::borrow::Cow;
+use std::collections::HashSet;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
 
 use parking_lot::RwLock;
-use rustracing::sampler::AllSampler;
+use rustracing::sampler::{AllSampler, BoxSampler, ProbabilisticSampler, Sampler};
 use rustracing::tag::Tag;
 use rustracing_jaeger::reporter::JaegerCompactReporter;
 use rustracing_jaeger::span::{
-    Span, SpanContext, SpanContextState, SpanContextStateBuilder, TraceId,
+    Span, SpanContext, SpanContextState, SpanContextStateBuilder, SpanSender, TraceId,
 };
 use rustracing_jaeger::Tracer;
 
+use crate::event::SpanEvents;
+use crate::guard::{current_span, CurrentSpan, Entered};
+use crate::name::{SpanNames, DEFAULT_MAX_SPAN_NAMES};
+use crate::reporter::BatchReporter;
+
+pub use crate::correlation::{span_id, trace_id, Correlation};
+pub use crate::filter::SpanFilter;
+pub use crate::task::{child_of, follows_from, spawn};
+
+type StartSpanOptions<'a> =
+    rustracing_jaeger::span::StartSpanOptions<'a, BoxSampler<SpanContextState>>;
+
 const SPAN_CHANNEL_SIZE: usize = 1024 * 1024;
 const DEFAULT_SPAN_BATCH_SIZE: usize = 20;
+const DEFAULT_MAX_PACKET_SIZE: usize = 65_000;
+
+// Tag of spans faster than their slow threshold, dropped by the reporter.
+pub(crate) const DISCARD_TAG: &str = "muta_apm.discard";
 
 lazy_static::lazy_static! {
     pub static ref MUTA_TRACER: MutaTracer = MutaTracer::new();
 }
 
 pub fn global_tracer_register(service_name: &str, udp_addr: SocketAddr, batch_size: Option<usize>) {
+    let mut config = TracerConfig::new(service_name, udp_addr);
+    if let Some(batch_size) = batch_size {
+        config = config.batch_size(batch_size);
+    }
+
+    register_with(config);
+}
+
+/// Options of `register_with`, e.g.
+/// `TracerConfig::new("muta", addr).max_packet_size(8192)`.
+#[derive(Clone, Debug)]
+pub struct TracerConfig {
+    service_name:    String,
+    udp_addr:        SocketAddr,
+    batch_size:      usize,
+    max_packet_size: usize,
+    span_filter:     Option<SpanFilter>,
+}
+
+impl TracerConfig {
+    pub fn new(service_name: &str, udp_addr: SocketAddr) -> Self {
+        TracerConfig {
+            service_name: service_name.to_owned(),
+            udp_addr,
+            batch_size: DEFAULT_SPAN_BATCH_SIZE,
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+            span_filter: None,
+        }
+    }
+
+    /// Number of spans reported together, 20 by default.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Maximum size of the udp packets sent to the agent, 65000 bytes by
+    /// default. Batches are split to fit.
+    pub fn max_packet_size(mut self, max_packet_size: usize) -> Self {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+
+    /// Replace the filter set with `MutaTracer::set_span_filter`, if any.
+    pub fn span_filter(mut self, span_filter: SpanFilter) -> Self {
+        self.span_filter = Some(span_filter);
+        self
+    }
+}
+
+pub fn register_with(config: TracerConfig) {
     let (span_tx, span_rx) = crossbeam_channel::bounded(SPAN_CHANNEL_SIZE);
-    let batch_size = batch_size.unwrap_or_else(|| DEFAULT_SPAN_BATCH_SIZE);
-    let mut reporter = JaegerCompactReporter::new(service_name).unwrap();
-    let mut tracer = MUTA_TRACER.inner.write();
-    *tracer = Some(Tracer::with_sender(AllSampler, span_tx));
+    let mut reporter = JaegerCompactReporter::new(&config.service_name).unwrap();
+    panic::install_hook();
+    MUTA_TRACER.set_tracer(span_tx, config.span_filter);
 
     reporter
-        .set_agent_addr(udp_addr)
+        .set_agent_addr(config.udp_addr)
         .expect("set udp addr error");
 
-    let mut batch_spans = Vec::with_capacity(batch_size + 1);
-    std::thread::spawn(move || loop {
-        if let Ok(finished_span) = span_rx.recv() {
-            batch_spans.push(finished_span);
-
-            if batch_spans.len() >= batch_size {
-                let enough_spans = batch_spans.drain(..).collect::<Vec<_>>();
-                if let Err(err) = reporter.report(&enough_spans) {
-                    log::warn!("jaeger report {}", err);
-                }
-            }
-        }
-    });
+    let batch_reporter = BatchReporter::new(
+        reporter,
+        &config.service_name,
+        config.batch_size,
+        config.max_packet_size,
+    );
+    std::thread::spawn(move || batch_reporter.run(span_rx));
 }
 
-#[derive(Default)]
 pub struct MutaTracer {
-    pub(crate) inner: RwLock<Option<Tracer>>,
+    pub(crate) inner:   RwLock<Option<Tracer>>,
+    pub(crate) span_tx: RwLock<Option<SpanSender>>,
+    enabled:            AtomicBool,
+    enabled_kinds:      RwLock<Option<HashSet<String>>>,
+    span_filter:        RwLock<SpanFilter>,
+    span_names:         RwLock<SpanNames>,
+    // In milliseconds, 0 if unset.
+    slow_threshold_ms:  AtomicU64,
+}
+
+impl Default for MutaTracer {
+    fn default() -> Self {
+        MutaTracer::new()
+    }
 }
 
 impl MutaTracer {
     pub fn new() -> Self {
         MutaTracer {
-            inner: RwLock::new(None),
+            inner:             RwLock::new(None),
+            span_tx:           RwLock::new(None),
+            enabled:           AtomicBool::new(true),
+            enabled_kinds:     RwLock::new(None),
+            span_filter:       RwLock::new(SpanFilter::new()),
+            span_names:        RwLock::new(SpanNames::new(DEFAULT_MAX_SPAN_NAMES)),
+            slow_threshold_ms: AtomicU64::new(0),
+        }
+    }
+
+    /// Resume span creation after `disable`.
+    pub fn enable(&self) {
+        self.enabled.store(true, Ordering::SeqCst);
+    }
+
+    /// Pause span creation, `span` and `child_of_span` return `None` until
+    /// `enable` is called.
+    pub fn disable(&self) {
+        self.enabled.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    /// Replace the sampler of the registered tracer, spans already started
+    /// are not affected. Do nothing if no tracer is registered.
+    pub fn set_sampler<S>(&self, sampler: S)
+    where
+        S: Sampler<SpanContextState> + Send + Sync + 'static,
+    {
+        if let Some(span_tx) = self.span_tx.read().as_ref() {
+            *self.inner.write() = Some(Tracer::with_sender(sampler, span_tx.clone()));
         }
     }
 
+    /// Sample spans with the given probability, which must be in `[0.0,
+    /// 1.0]`.
+    pub fn set_sample_rate(&self, rate: f64) -> rustracing::Result<()> {
+        let sampler = ProbabilisticSampler::new(rate)?;
+        self.set_sampler(sampler);
+        Ok(())
+    }
+
+    /// Only create spans whose kind is in the given list, the kind of a span
+    /// is the part of its name before the first `.`.
+    pub fn set_enabled_kinds<I, K>(&self, kinds: I)
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        let kinds = kinds.into_iter().map(Into::into).collect();
+        *self.enabled_kinds.write() = Some(kinds);
+    }
+
+    /// Create spans of all kinds again.
+    pub fn clear_enabled_kinds(&self) {
+        *self.enabled_kinds.write() = None;
+    }
+
+    /// Replace the allow and deny lists, checked on top of the enabled kinds.
+    pub fn set_span_filter(&self, span_filter: SpanFilter) {
+        *self.span_filter.write() = span_filter;
+    }
+
+    /// Discard spans of `#[tracing_span]` functions which finish faster than
+    /// `threshold`, unless they errored or one of their descendants is
+    /// reported. Functions with a `slow_threshold_ms` option use their own.
+    pub fn set_slow_threshold(&self, threshold: Option<Duration>) {
+        let threshold_ms = threshold.map(|t| t.as_millis() as u64).unwrap_or(0);
+        self.slow_threshold_ms.store(threshold_ms, Ordering::SeqCst);
+    }
+
+    pub fn slow_threshold(&self) -> Option<Duration> {
+        match self.slow_threshold_ms.load(Ordering::SeqCst) {
+            0 => None,
+            threshold_ms => Some(Duration::from_millis(threshold_ms)),
+        }
+    }
+
+    /// Limit the number of distinct names produced by each span name
+    /// template, 64 by default.
+    pub fn set_max_span_names(&self, max_names: usize) {
+        self.span_names.write().set_max_names(max_names);
+    }
+
+    /// The name of a span formatted at runtime from `template`, like
+    /// `#[tracing_span(name = "exec_{service}")]`. The template itself is
+    /// used once it produced too many distinct names, or if tracing is
+    /// disabled or no tracer is registered, in which case `format` isn't
+    /// called.
+    pub fn span_name<F>(&self, template: &'static str, format: F) -> Cow<'static, str>
+    where
+        F: FnOnce() -> String,
+    {
+        if !self.is_enabled() || self.inner.read().is_none() {
+            return Cow::Borrowed(template);
+        }
+
+        let name = format();
+        let known = self.span_names.read().contains(template, &name);
+        if known || self.span_names.write().insert(template, &name) {
+            Cow::Owned(name)
+        } else {
+            Cow::Borrowed(template)
+        }
+    }
+
+    // Set by `register_with`, a filter set before is kept unless the config
+    // has its own.
+    fn set_tracer(&self, span_tx: SpanSender, span_filter: Option<SpanFilter>) {
+        if let Some(span_filter) = span_filter {
+            *self.span_filter.write() = span_filter;
+        }
+        *self.span_tx.write() = Some(span_tx.clone());
+        *self.inner.write() = Some(Tracer::with_sender(AllSampler, span_tx));
+    }
+
+    fn is_span_enabled(&self, opt_name: &str) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+
+        if let Some(kinds) = self.enabled_kinds.read().as_ref() {
+            let kind = opt_name.split('.').next().unwrap_or_default();
+            if !kinds.contains(kind) {
+                return false;
+            }
+        }
+
+        self.span_filter.read().is_allowed(opt_name)
+    }
+
     pub fn child_of_span<N: Into<Cow<'static, str>>>(
         &self,
         opt_name: N,
         parent_ctx: SpanContext,
         tags: Vec<Tag>,
     ) -> Option<Span> {
-        match self.inner.read().as_ref() {
-            Some(inner) => {
-                let mut span = inner.span(opt_name);
-                for tag in tags.into_iter() {
-                    span = span.tag(tag);
-                }
-                Some(span.child_of(&parent_ctx).start())
-            }
-            None => None,
-        }
+        self.start_span(opt_name, tags, |options| options.child_of(&parent_ctx))
+    }
+
+    /// Start a span referencing `parent_ctx` with `follows_from`, for work
+    /// the parent doesn't wait for, like a gossip broadcast.
+    pub fn follows_from_span<N: Into<Cow<'static, str>>>(
+        &self,
+        opt_name: N,
+        parent_ctx: SpanContext,
+        tags: Vec<Tag>,
+    ) -> Option<Span> {
+        self.start_span(opt_name, tags, |options| options.follows_from(&parent_ctx))
     }
 
     pub fn span<N: Into<Cow<'static, str>>>(&self, opt_name: N, tags: Vec<Tag>) -> Option<Span> {
-        match self.inner.read().as_ref() {
-            Some(inner) => {
-                let mut span = inner.span(opt_name);
-                for tag in tags.into_iter() {
-                    span = span.tag(tag);
-                }
-                Some(span.start())
-            }
-            None => None,
+        self.start_span(opt_name, tags, |options| options)
+    }
+
+    /// Start a span child of the span of `ctx`, or a new trace if `ctx` holds
+    /// no span. `None` under a span which isn't sampled, so that a trace is
+    /// sampled whole instead of restarted by each descendant.
+    pub fn child_of_ctx<N: Into<Cow<'static, str>>>(
+        &self,
+        opt_name: N,
+        ctx: &creep::Context,
+        tags: Vec<Tag>,
+    ) -> Option<Span> {
+        match Self::parent_span(ctx)? {
+            Some(parent_ctx) => self.child_of_span(opt_name, parent_ctx.clone(), tags),
+            None => self.span(opt_name, tags),
         }
     }
 
+    /// Like `child_of_ctx`, with a `follows_from` reference.
+    pub fn follows_from_ctx<N: Into<Cow<'static, str>>>(
+        &self,
+        opt_name: N,
+        ctx: &creep::Context,
+        tags: Vec<Tag>,
+    ) -> Option<Span> {
+        match Self::parent_span(ctx)? {
+            Some(parent_ctx) => self.follows_from_span(opt_name, parent_ctx.clone(), tags),
+            None => self.span(opt_name, tags),
+        }
+    }
+
+    // The span of `ctx`, `None` if it isn't sampled.
+    fn parent_span(ctx: &creep::Context) -> Option<Option<&SpanContext>> {
+        if ctx.get::<bool>("parent_span_unsampled") == Some(&true) {
+            return None;
+        }
+
+        Some(
+            ctx.get::<Option<SpanContext>>("parent_span_ctx")
+                .and_then(Option::as_ref),
+        )
+    }
+
+    // `None` if the span is disabled or no tracer is registered, `reference`
+    // adds the references of the span.
+    fn start_span<N: Into<Cow<'static, str>>>(
+        &self,
+        opt_name: N,
+        tags: Vec<Tag>,
+        reference: impl FnOnce(StartSpanOptions<'_>) -> StartSpanOptions<'_>,
+    ) -> Option<Span> {
+        let opt_name = opt_name.into();
+        if !self.is_span_enabled(&opt_name) {
+            return None;
+        }
+
+        let inner = self.inner.read();
+        let mut options = inner.as_ref()?.span(opt_name);
+        for tag in tags.into_iter() {
+            options = options.tag(tag);
+        }
+        Some(reference(options).start())
+    }
+
     pub fn new_state(trace_id: TraceId, span_id: u64) -> SpanContextState {
         SpanContextStateBuilder::new()
             .trace_id(trace_id)
@@ -111,4 +385,196 @@ impl MutaTracer {
         let span = SpanContext::new(span_state, vec![]);
         ctx.with_value::<Option<SpanContext>>("parent_span_ctx", Some(span))
     }
+
+    /// Make the span of `ctx` the current one of the thread until the
+    /// returned value is dropped, so that `log` records and `tracing` spans of
+    /// code not taking a context are attached to it.
+    pub fn enter_context(ctx: &creep::Context) -> Entered {
+        let current = match ctx.get::<Option<SpanContext>>("parent_span_ctx") {
+            Some(Some(span_ctx)) => Some(CurrentSpan {
+                span_ctx: span_ctx.clone(),
+                events:   ctx.get::<SpanEvents>("parent_span_events").cloned(),
+            }),
+            _ => None,
+        };
+
+        Entered::new(current)
+    }
+
+    /// `ctx` with the current span of the thread as parent, e.g. to call a
+    /// `#[tracing_span]` function from code instrumented with `tracing`.
+    pub fn current_context(ctx: creep::Context) -> creep::Context {
+        let current = match current_span() {
+            Some(current) => current,
+            None => return ctx,
+        };
+
+        let ctx = ctx.with_value::<Option<SpanContext>>("parent_span_ctx", Some(current.span_ctx));
+        match current.events {
+            Some(events) => ctx.with_value("parent_span_events", events),
+            None => ctx,
+        }
+    }
+}
+
+#[cfg(test)]
+lazy_static::lazy_static! {
+    // `MUTA_TRACER` is registered once for the unit tests of every module,
+    // spans received for another test are kept until it asks for them.
+    static ref TEST_SPANS: (
+        rustracing_jaeger::span::SpanReceiver,
+        parking_lot::Mutex<Vec<rustracing_jaeger::span::FinishedSpan>>,
+    ) = {
+        let (span_tx, span_rx) = crossbeam_channel::unbounded();
+        MUTA_TRACER.set_tracer(span_tx, None);
+        (span_rx, parking_lot::Mutex::new(Vec::new()))
+    };
+}
+
+/// Register `MUTA_TRACER` for unit tests.
+#[cfg(test)]
+pub(crate) fn register_test_tracer() {
+    lazy_static::initialize(&TEST_SPANS);
+}
+
+/// The next span named `opt_name` finished by `MUTA_TRACER`, span names must
+/// be unique across unit tests.
+#[cfg(test)]
+pub(crate) fn recv_test_span(opt_name: &str) -> rustracing_jaeger::span::FinishedSpan {
+    let (span_rx, pending) = &*TEST_SPANS;
+    let mut pending = pending.lock();
+
+    loop {
+        if let Some(pos) = pending
+            .iter()
+            .position(|span| span.operation_name() == opt_name)
+        {
+            return pending.remove(pos);
+        }
+        pending.push(span_rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rustracing_jaeger::span::{SpanContext, SpanReceiver};
+
+    use super::{MutaTracer, SpanFilter};
+    use crate::guard::SpanGuard;
+
+    fn registered_tracer() -> (MutaTracer, SpanReceiver) {
+        let (span_tx, span_rx) = crossbeam_channel::unbounded();
+        let tracer = MutaTracer::new();
+        tracer.set_tracer(span_tx, None);
+
+        (tracer, span_rx)
+    }
+
+    // Whether `span`, `child_of_span` and `follows_from_span` start a span.
+    fn is_started(
+        tracer: &MutaTracer,
+        opt_name: &'static str,
+        parent_ctx: &SpanContext,
+    ) -> [bool; 3] {
+        [
+            tracer.span(opt_name, Vec::new()).is_some(),
+            tracer
+                .child_of_span(opt_name, parent_ctx.clone(), Vec::new())
+                .is_some(),
+            tracer
+                .follows_from_span(opt_name, parent_ctx.clone(), Vec::new())
+                .is_some(),
+        ]
+    }
+
+    #[test]
+    fn test_enabled_kinds() {
+        let (tracer, _span_rx) = registered_tracer();
+        let parent = tracer.span("consensus.commit", Vec::new()).unwrap();
+        let parent_ctx = parent.context().cloned().unwrap();
+
+        tracer.set_enabled_kinds(vec!["storage"]);
+        assert_eq!(
+            is_started(&tracer, "storage.insert", &parent_ctx),
+            [true; 3]
+        );
+        assert_eq!(
+            is_started(&tracer, "mempool.flush", &parent_ctx),
+            [false; 3]
+        );
+
+        tracer.set_enabled_kinds(Vec::<String>::new());
+        assert_eq!(
+            is_started(&tracer, "storage.insert", &parent_ctx),
+            [false; 3]
+        );
+
+        tracer.clear_enabled_kinds();
+        assert_eq!(
+            is_started(&tracer, "storage.insert", &parent_ctx),
+            [true; 3]
+        );
+        assert_eq!(is_started(&tracer, "mempool.flush", &parent_ctx), [true; 3]);
+    }
+
+    #[test]
+    fn test_unsampled_parent() {
+        let (tracer, _span_rx) = registered_tracer();
+        tracer.set_sample_rate(0.0).unwrap();
+
+        // Started, but not sampled.
+        let parent = tracer.span("consensus.commit", Vec::new()).unwrap();
+        assert!(parent.context().is_none());
+
+        let parent = SpanGuard::new(Some(parent), None, None);
+        let child_ctx = parent.child_ctx(&creep::Context::new()).unwrap();
+        assert!(tracer
+            .child_of_ctx("storage.insert", &child_ctx, Vec::new())
+            .is_none());
+        assert!(tracer
+            .follows_from_ctx("storage.insert", &child_ctx, Vec::new())
+            .is_none());
+
+        // A context without span still starts a trace.
+        assert!(tracer
+            .child_of_ctx("storage.insert", &creep::Context::new(), Vec::new())
+            .is_some());
+    }
+
+    #[test]
+    fn test_span_name() {
+        let tracer = MutaTracer::new();
+        let name = tracer.span_name("exec_{service}", || panic!("formatted without tracer"));
+        assert_eq!(name, "exec_{service}");
+
+        let (tracer, _span_rx) = registered_tracer();
+        assert_eq!(
+            tracer.span_name("exec_{service}", || "exec_asset".to_owned()),
+            "exec_asset"
+        );
+
+        tracer.disable();
+        let name = tracer.span_name("exec_{service}", || panic!("formatted while disabled"));
+        assert_eq!(name, "exec_{service}");
+    }
+
+    #[test]
+    fn test_filter_kept_at_registration() {
+        let tracer = MutaTracer::new();
+        let mut span_filter = SpanFilter::new();
+        span_filter.set_deny_kinds(vec!["mempool"]);
+        tracer.set_span_filter(span_filter);
+
+        let (span_tx, _span_rx) = crossbeam_channel::unbounded();
+        tracer.set_tracer(span_tx, None);
+        assert!(tracer.span("mempool.flush", Vec::new()).is_none());
+        assert!(tracer.span("storage.insert", Vec::new()).is_some());
+
+        let mut span_filter = SpanFilter::new();
+        span_filter.set_deny_kinds(vec!["storage"]);
+        tracer.set_enabled_kinds(vec!["mempool", "storage"]);
+        tracer.set_span_filter(span_filter);
+        assert!(tracer.span("mempool.flush", Vec::new()).is_some());
+        assert!(tracer.span("storage.insert", Vec::new()).is_none());
+    }
 }
@@ -0,0 +1,160 @@
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use rustracing::log::LogField;
+
+use crate::guard::current_span;
+
+/// A `log::Log` forwarding records to an inner logger, with the trace id of
+/// the instrumented call they're emitted in as prefix, e.g. `[trace_id=..]`.
+/// Records at least as severe as the span level, warn by default, are also
+/// logged on the span of the call.
+pub struct ApmLogger<L> {
+    inner:      L,
+    span_level: Level,
+}
+
+impl<L: Log + 'static> ApmLogger<L> {
+    pub fn new(inner: L) -> Self {
+        ApmLogger {
+            inner,
+            span_level: Level::Warn,
+        }
+    }
+
+    pub fn set_span_level(&mut self, level: Level) {
+        self.span_level = level;
+    }
+
+    /// Install as the global logger, see `log::set_boxed_logger`.
+    pub fn init(self, max_level: LevelFilter) -> Result<(), SetLoggerError> {
+        log::set_boxed_logger(Box::new(self))?;
+        log::set_max_level(max_level);
+        Ok(())
+    }
+}
+
+impl<L: Log> Log for ApmLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.span_level || self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let current = match current_span() {
+            Some(current) => current,
+            // `enabled` of this logger lets the records of the span level
+            // through, the inner logger may not want them.
+            None if self.inner.enabled(record.metadata()) => return self.inner.log(record),
+            None => return,
+        };
+
+        let events = current.events.as_ref();
+        if let Some(events) = events.filter(|_| record.level() <= self.span_level) {
+            events.record(vec![
+                LogField::new("event", "log"),
+                LogField::new("level", record.level().to_string()),
+                LogField::new("target", record.target().to_owned()),
+                LogField::new("message", record.args().to_string()),
+            ]);
+        }
+
+        if self.inner.enabled(record.metadata()) {
+            self.inner.log(
+                &Record::builder()
+                    .metadata(record.metadata().clone())
+                    .args(format_args!(
+                        "[trace_id={}] {}",
+                        current.span_ctx.state().trace_id(),
+                        record.args()
+                    ))
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            );
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use log::{Level, Log, Metadata, Record};
+    use parking_lot::Mutex;
+    use rustracing::sampler::AllSampler;
+    use rustracing_jaeger::Tracer;
+
+    use super::ApmLogger;
+    use crate::guard::{CurrentSpan, Entered};
+
+    // Keeps the messages it's given, whether it's enabled for them or not.
+    struct CaptureLogger {
+        level:    Level,
+        messages: Mutex<Vec<String>>,
+    }
+
+    impl Log for CaptureLogger {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.level() <= self.level
+        }
+
+        fn log(&self, record: &Record) {
+            self.messages.lock().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn apm_logger(level: Level) -> ApmLogger<CaptureLogger> {
+        let mut logger = ApmLogger::new(CaptureLogger {
+            level,
+            messages: Mutex::new(Vec::new()),
+        });
+        logger.set_span_level(Level::Debug);
+        logger
+    }
+
+    fn log(logger: &ApmLogger<CaptureLogger>, level: Level, message: &str) {
+        logger.log(
+            &Record::builder()
+                .level(level)
+                .args(format_args!("{}", message))
+                .build(),
+        );
+    }
+
+    #[test]
+    fn test_log_without_span() {
+        let logger = apm_logger(Level::Info);
+        assert!(logger.enabled(&Metadata::builder().level(Level::Debug).build()));
+
+        log(&logger, Level::Info, "info");
+        log(&logger, Level::Debug, "debug");
+        assert_eq!(*logger.inner.messages.lock(), vec!["info"]);
+    }
+
+    #[test]
+    fn test_log_in_span() {
+        let (span_tx, _span_rx) = crossbeam_channel::unbounded();
+        let tracer = Tracer::with_sender(AllSampler, span_tx);
+        let span = tracer.span("consensus.commit").start();
+        let span_ctx = span.context().unwrap().clone();
+        let trace_id = span_ctx.state().trace_id();
+
+        let logger = apm_logger(Level::Info);
+        {
+            let _entered = Entered::new(Some(CurrentSpan {
+                span_ctx,
+                events: None,
+            }));
+            log(&logger, Level::Info, "info");
+            log(&logger, Level::Debug, "debug");
+        }
+
+        assert_eq!(*logger.inner.messages.lock(), vec![format!(
+            "[trace_id={}] info",
+            trace_id
+        )]);
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::{HashMap, HashSet};
+
+pub(crate) const DEFAULT_MAX_SPAN_NAMES: usize = 64;
+
+/// Span names formatted at runtime, grouped by template. Once a template has
+/// produced `max_names` distinct names, further names are replaced by the
+/// template itself, so a badly chosen argument can't flood jaeger with
+/// operations.
+#[derive(Debug)]
+pub(crate) struct SpanNames {
+    max_names: usize,
+    names:     HashMap<&'static str, HashSet<String>>,
+}
+
+impl SpanNames {
+    pub fn new(max_names: usize) -> Self {
+        SpanNames {
+            max_names,
+            names: HashMap::new(),
+        }
+    }
+
+    pub fn set_max_names(&mut self, max_names: usize) {
+        self.max_names = max_names;
+    }
+
+    pub fn contains(&self, template: &str, name: &str) -> bool {
+        self.names
+            .get(template)
+            .map(|names| names.contains(name))
+            .unwrap_or(false)
+    }
+
+    /// Record `name`, return false if the template is already at the limit.
+    pub fn insert(&mut self, template: &'static str, name: &str) -> bool {
+        let max_names = self.max_names;
+        let names = self.names.entry(template).or_insert_with(HashSet::new);
+
+        if names.contains(name) {
+            return true;
+        }
+        if names.len() >= max_names {
+            return false;
+        }
+
+        names.insert(name.to_owned());
+        if names.len() == max_names {
+            log::warn!(
+                "muta-apm: span name template {:?} reached {} names, using the template",
+                template,
+                max_names
+            );
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SpanNames;
+
+    #[test]
+    fn test_span_names() {
+        let mut names = SpanNames::new(2);
+        let template = "service.exec_{service}";
+
+        assert!(names.insert(template, "service.exec_asset"));
+        assert!(names.insert(template, "service.exec_metadata"));
+        assert!(names.insert(template, "service.exec_asset"));
+        assert!(!names.insert(template, "service.exec_riscv"));
+        assert!(names.contains(template, "service.exec_asset"));
+        assert!(!names.contains(template, "service.exec_riscv"));
+
+        assert!(names.insert("service.query_{service}", "service.query_asset"));
+
+        names.set_max_names(3);
+        assert!(names.insert(template, "service.exec_riscv"));
+    }
+}
@@ -0,0 +1,97 @@
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Once;
+use std::task::{Context, Poll};
+use std::thread;
+
+use crate::guard::SpanGuard;
+
+static INSTALL_HOOK: Once = Once::new();
+
+thread_local! {
+    // Message and location of the last panic of this thread, set by the hook
+    // before unwinding starts.
+    static LAST_PANIC: RefCell<Option<(String, Option<String>)>> = RefCell::new(None);
+}
+
+/// Install a panic hook recording the message and location of panics, so
+/// that spans dropped during unwinding can report them. The previous hook is
+/// still called. Called by `register_with`, only the first call installs the
+/// hook.
+pub(crate) fn install_hook() {
+    INSTALL_HOOK.call_once(|| {
+        let prev_hook = std::panic::take_hook();
+
+        std::panic::set_hook(Box::new(move |info| {
+            let payload = info.payload();
+            let msg = if let Some(msg) = payload.downcast_ref::<&str>() {
+                (*msg).to_owned()
+            } else if let Some(msg) = payload.downcast_ref::<String>() {
+                msg.clone()
+            } else {
+                "Box<Any>".to_owned()
+            };
+            let location = info
+                .location()
+                .map(|loc| format!("{}:{}:{}", loc.file(), loc.line(), loc.column()));
+
+            LAST_PANIC.with(|last| *last.borrow_mut() = Some((msg, location)));
+            prev_hook(info);
+        }));
+    });
+}
+
+/// Message and location of the panic being unwound.
+pub(crate) fn last_panic() -> (String, Option<String>) {
+    LAST_PANIC
+        .with(|last| last.borrow().clone())
+        .unwrap_or_else(|| ("unknown panic".to_owned(), None))
+}
+
+// Marks the span if a poll of the wrapped future unwinds, executors catching
+// panics drop the future itself only after unwinding.
+struct PollGuard<'a> {
+    guard:     &'a mut SpanGuard,
+    panicking: bool,
+}
+
+impl<'a> Drop for PollGuard<'a> {
+    fn drop(&mut self) {
+        if !self.panicking && thread::panicking() {
+            self.guard.report_panic();
+        }
+    }
+}
+
+/// Report panics of an instrumented future to its span, the panic keeps
+/// unwinding. The span is the current one of the thread while polled.
+#[doc(hidden)]
+pub struct CatchPanic<'a, F> {
+    guard: &'a mut SpanGuard,
+    fut:   F,
+}
+
+impl<'a, F: Future> CatchPanic<'a, F> {
+    pub fn new(guard: &'a mut SpanGuard, fut: F) -> Self {
+        CatchPanic { guard, fut }
+    }
+}
+
+impl<'a, F: Future> Future for CatchPanic<'a, F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: `fut` is pinned whenever `self` is, and never moved out.
+        // `CatchPanic` has no `Drop` impl, and is only `Unpin` if `F` is.
+        let this = unsafe { self.get_unchecked_mut() };
+        let fut = unsafe { Pin::new_unchecked(&mut this.fut) };
+        let _entered = this.guard.enter();
+        let _poll_guard = PollGuard {
+            guard:     this.guard,
+            panicking: thread::panicking(),
+        };
+
+        fut.poll(cx)
+    }
+}
@@ -0,0 +1,274 @@
+use rustracing::log::{Log, LogField};
+use rustracing::sampler::AllSampler;
+use rustracing::tag::{Tag, TagValue};
+use rustracing_jaeger::reporter::JaegerCompactReporter;
+use rustracing_jaeger::span::{FinishedSpan, SpanContext, SpanReceiver};
+use rustracing_jaeger::Tracer;
+
+use crate::DISCARD_TAG;
+
+// Upper bounds of the compact thrift encoding of jaeger spans, from the
+// widest encoding of each field: a field header takes 1 byte, an i64 varint
+// at most 10, an i32 varint and a string or list length at most 5. Process
+// tags, hostname included, fit in the batch overhead.
+const FIELD_HEADER: usize = 1;
+const STRUCT_STOP: usize = 1;
+const I64_FIELD: usize = FIELD_HEADER + 10;
+const I32_FIELD: usize = FIELD_HEADER + 5;
+const LEN_FIELD: usize = FIELD_HEADER + 5;
+// The element type byte, then the length.
+const LIST_FIELD: usize = LEN_FIELD + 1;
+
+const BATCH_OVERHEAD: usize = 512;
+// Trace id low and high, span id, parent span id, start time and duration,
+// flags, operation name, references, tags and logs.
+const SPAN_OVERHEAD: usize = 6 * I64_FIELD + I32_FIELD + LEN_FIELD + 3 * LIST_FIELD + STRUCT_STOP;
+// Reference type, trace id low and high, span id.
+const REFERENCE_SIZE: usize = I32_FIELD + 3 * I64_FIELD + STRUCT_STOP;
+// Key, value type and string value, a numeric value takes up to 10 bytes.
+const TAG_OVERHEAD: usize = LEN_FIELD + I32_FIELD + LEN_FIELD + STRUCT_STOP;
+const NUMERIC_VALUE_SIZE: usize = 10;
+// Timestamp and fields.
+const LOG_OVERHEAD: usize = I64_FIELD + LIST_FIELD + STRUCT_STOP;
+// Room kept for the log counting the logs dropped from a span.
+const TRUNCATED_LOG_SIZE: usize = LOG_OVERHEAD + TAG_OVERHEAD + 64;
+
+pub(crate) struct BatchReporter {
+    reporter:    JaegerCompactReporter,
+    batch_size:  usize,
+    span_budget: usize,
+}
+
+impl BatchReporter {
+    pub(crate) fn new(
+        reporter: JaegerCompactReporter,
+        service_name: &str,
+        batch_size: usize,
+        max_packet_size: usize,
+    ) -> Self {
+        let span_budget = max_packet_size.saturating_sub(BATCH_OVERHEAD + service_name.len());
+
+        BatchReporter {
+            reporter,
+            batch_size,
+            span_budget,
+        }
+    }
+
+    pub(crate) fn run(self, span_rx: SpanReceiver) {
+        let mut batch_spans = Vec::with_capacity(self.batch_size + 1);
+
+        while let Ok(finished_span) = span_rx.recv() {
+            if is_discarded(&finished_span) {
+                continue;
+            }
+            batch_spans.push(finished_span);
+
+            if batch_spans.len() >= self.batch_size {
+                let enough_spans = batch_spans.drain(..).collect::<Vec<_>>();
+                self.report(enough_spans);
+            }
+        }
+    }
+
+    fn report(&self, spans: Vec<FinishedSpan>) {
+        for packet in split_batch(spans, self.span_budget).iter() {
+            if let Err(err) = self.reporter.report(packet) {
+                log::warn!("jaeger report {}", err);
+            }
+        }
+    }
+}
+
+fn is_discarded(span: &FinishedSpan) -> bool {
+    span.tags().iter().any(|tag| tag.name() == DISCARD_TAG)
+}
+
+/// Split spans into packets whose estimated encoded size fits in the budget.
+/// A span which is too big on its own is reported alone, with its logs
+/// truncated.
+fn split_batch(spans: Vec<FinishedSpan>, budget: usize) -> Vec<Vec<FinishedSpan>> {
+    let mut packets = Vec::new();
+    let mut packet = Vec::new();
+    let mut packet_size = 0;
+
+    for span in spans.into_iter() {
+        let span_size = estimate_span_size(&span);
+
+        if span_size > budget {
+            match truncate_logs(&span, budget) {
+                Some(truncated) => packets.push(vec![truncated]),
+                None => {
+                    log::warn!(
+                        "muta-apm: failed to truncate logs of {}",
+                        span.operation_name()
+                    );
+                    packets.push(vec![span]);
+                }
+            }
+            continue;
+        }
+
+        if packet_size + span_size > budget && !packet.is_empty() {
+            packets.push(packet.drain(..).collect());
+            packet_size = 0;
+        }
+
+        packet.push(span);
+        packet_size += span_size;
+    }
+
+    if !packet.is_empty() {
+        packets.push(packet);
+    }
+
+    packets
+}
+
+fn estimate_span_size(span: &FinishedSpan) -> usize {
+    SPAN_OVERHEAD
+        + span.operation_name().len()
+        + span.references().len() * REFERENCE_SIZE
+        + span.tags().iter().map(estimate_tag_size).sum::<usize>()
+        + span.logs().iter().map(estimate_log_size).sum::<usize>()
+}
+
+fn estimate_tag_size(tag: &Tag) -> usize {
+    let value_size = match tag.value() {
+        TagValue::String(s) => s.len(),
+        _ => NUMERIC_VALUE_SIZE,
+    };
+
+    TAG_OVERHEAD + tag.name().len() + value_size
+}
+
+fn estimate_log_size(log: &Log) -> usize {
+    LOG_OVERHEAD
+        + log
+            .fields()
+            .iter()
+            .map(|field| TAG_OVERHEAD + field.name().len() + field.value().len())
+            .sum::<usize>()
+}
+
+/// Rebuild the finished span with the same context, timestamps, references
+/// and tags, but keep only the logs that fit in the budget. `None` if the
+/// rebuilt span isn't sent on finish.
+fn truncate_logs(span: &FinishedSpan, budget: usize) -> Option<FinishedSpan> {
+    let (span_tx, span_rx) = crossbeam_channel::bounded(1);
+    let tracer = Tracer::with_sender(AllSampler, span_tx);
+
+    let mut options = tracer
+        .span(span.operation_name().to_owned())
+        .start_time(span.start_time());
+    for reference in span.references().iter() {
+        let parent_ctx = SpanContext::new(reference.span().clone(), vec![]);
+        options = if reference.is_child_of() {
+            options.child_of(&parent_ctx)
+        } else {
+            options.follows_from(&parent_ctx)
+        };
+    }
+    for tag in span.tags().iter() {
+        options = options.tag(tag.clone());
+    }
+
+    let mut size = TRUNCATED_LOG_SIZE
+        + SPAN_OVERHEAD
+        + span.operation_name().len()
+        + span.references().len() * REFERENCE_SIZE
+        + span.tags().iter().map(estimate_tag_size).sum::<usize>();
+    let mut dropped = 0;
+
+    let mut rebuilt = options.start_with_state(span.context().state().clone());
+    for log in span.logs().iter() {
+        let log_size = estimate_log_size(log);
+        if size + log_size > budget {
+            dropped += 1;
+            continue;
+        }
+
+        size += log_size;
+        rebuilt.log(|builder| {
+            builder.time(log.time());
+            for field in log.fields().iter() {
+                builder.field(field.clone());
+            }
+        });
+    }
+
+    if dropped > 0 {
+        rebuilt.log(|builder| {
+            builder.field(LogField::new(
+                "truncated_logs",
+                format!("{} logs dropped to fit udp packet", dropped),
+            ));
+        });
+    }
+
+    let finish_time = span.finish_time();
+    rebuilt.set_finish_time(|| finish_time);
+    drop(rebuilt);
+
+    span_rx.try_recv().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use rustracing::log::LogField;
+    use rustracing::sampler::AllSampler;
+    use rustracing::tag::Tag;
+    use rustracing_jaeger::span::FinishedSpan;
+    use rustracing_jaeger::Tracer;
+
+    use super::{estimate_span_size, split_batch};
+
+    fn finished_span(log_count: usize) -> FinishedSpan {
+        let (span_tx, span_rx) = crossbeam_channel::unbounded();
+        let tracer = Tracer::with_sender(AllSampler, span_tx);
+
+        let mut span = tracer
+            .span("consensus.commit")
+            .tag(Tag::new("kind", "consensus"))
+            .start();
+        for i in 0..log_count {
+            span.log(|log| {
+                log.field(LogField::new("height", i.to_string()));
+            });
+        }
+        drop(span);
+
+        span_rx.try_recv().unwrap()
+    }
+
+    #[test]
+    fn test_split_batch() {
+        let spans = (0..10).map(|_| finished_span(2)).collect::<Vec<_>>();
+        let budget = estimate_span_size(&spans[0]) * 3;
+
+        let packets = split_batch(spans, budget);
+        let packet_lens = packets.iter().map(Vec::len).collect::<Vec<_>>();
+        assert_eq!(packet_lens, vec![3, 3, 3, 1]);
+        for packet in packets.iter() {
+            assert!(packet.iter().map(estimate_span_size).sum::<usize>() <= budget);
+        }
+    }
+
+    #[test]
+    fn test_truncate_logs() {
+        let span = finished_span(100);
+        let budget = estimate_span_size(&span) / 2;
+
+        let packets = split_batch(vec![span], budget);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].len(), 1);
+
+        let truncated = &packets[0][0];
+        assert!(estimate_span_size(truncated) <= budget);
+        assert!(truncated.logs().len() < 100);
+        assert!(truncated.tags().iter().any(|tag| tag.name() == "kind"));
+
+        let last_log = truncated.logs().last().unwrap();
+        assert_eq!(last_log.fields()[0].name(), "truncated_logs");
+    }
+}
@@ -0,0 +1,54 @@
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+thread_local! {
+    static RNG_STATE: Cell<u64> = Cell::new(seed());
+}
+
+/// Whether a span of a `#[tracing_span(sample_rate = ..)]` function is
+/// started, true with probability `rate`.
+#[doc(hidden)]
+pub fn sample(rate: f64) -> bool {
+    if rate >= 1.0 {
+        return true;
+    }
+    if rate <= 0.0 {
+        return false;
+    }
+
+    // Top 53 bits as a float in [0, 1).
+    let value = (next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+    value < rate
+}
+
+// xorshift64*, good enough to pick spans and cheaper than a lock.
+fn next_u64() -> u64 {
+    RNG_STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    })
+}
+
+// `RandomState` is randomly seeded per thread, the state must not be zero.
+fn seed() -> u64 {
+    RandomState::new().build_hasher().finish() | 1
+}
+
+#[cfg(test)]
+mod test {
+    use super::sample;
+
+    #[test]
+    fn test_sample() {
+        assert!((0..100).all(|_| sample(1.0)));
+        assert!((0..100).all(|_| !sample(0.0)));
+
+        let sampled = (0..10_000).filter(|_| sample(0.1)).count();
+        assert!(sampled > 700 && sampled < 1300, "sampled {}", sampled);
+    }
+}
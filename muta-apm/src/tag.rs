@@ -0,0 +1,234 @@
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::fmt::{Debug, Display};
+
+use rustracing::tag::TagValue;
+
+/// Convert a value to a typed tag value, so that numbers and booleans can
+/// be queried as such in jaeger. Values of types which don't implement this
+/// trait are recorded by `#[tracing_span]` through `Display`, or `Debug` for
+/// the arguments recorded by `fields(all)`.
+pub trait ToTagValue {
+    fn to_tag_value(&self) -> TagValue;
+}
+
+impl<T: ToTagValue + ?Sized> ToTagValue for &T {
+    fn to_tag_value(&self) -> TagValue {
+        (**self).to_tag_value()
+    }
+}
+
+impl ToTagValue for bool {
+    fn to_tag_value(&self) -> TagValue {
+        TagValue::Boolean(*self)
+    }
+}
+
+impl ToTagValue for str {
+    fn to_tag_value(&self) -> TagValue {
+        TagValue::String(Cow::Owned(self.to_owned()))
+    }
+}
+
+impl ToTagValue for String {
+    fn to_tag_value(&self) -> TagValue {
+        TagValue::String(Cow::Owned(self.clone()))
+    }
+}
+
+impl ToTagValue for Cow<'static, str> {
+    fn to_tag_value(&self) -> TagValue {
+        TagValue::String(self.clone())
+    }
+}
+
+// Jaeger only has signed 64 bits integer, values out of range are recorded as
+// string.
+macro_rules! impl_integer_tag_value {
+    ($($ty: ty),*) => {
+        $(
+            impl ToTagValue for $ty {
+                fn to_tag_value(&self) -> TagValue {
+                    match i64::try_from(*self) {
+                        Ok(value) => TagValue::Integer(value),
+                        Err(_) => TagValue::String(Cow::Owned(self.to_string())),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_integer_tag_value!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl ToTagValue for f32 {
+    fn to_tag_value(&self) -> TagValue {
+        TagValue::Float(f64::from(*self))
+    }
+}
+
+impl ToTagValue for f64 {
+    fn to_tag_value(&self) -> TagValue {
+        TagValue::Float(*self)
+    }
+}
+
+/// Cut string values longer than `max_len` bytes, at a char boundary.
+#[doc(hidden)]
+pub fn truncate_tag_value(value: TagValue, max_len: usize) -> TagValue {
+    match value {
+        TagValue::String(s) if s.len() > max_len => {
+            TagValue::String(Cow::Owned(truncate_str(s.into_owned(), max_len)))
+        }
+        value => value,
+    }
+}
+
+#[doc(hidden)]
+pub fn truncate_str(mut s: String, max_len: usize) -> String {
+    if s.len() <= max_len {
+        return s;
+    }
+
+    let mut end = max_len;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    s.truncate(end);
+    s.push_str("...");
+    s
+}
+
+// Autoref specialization used by `#[tracing_span]`, `(&TagValueWrap(&expr))
+// .tag_value()` prefers `ToTagValue` and falls back to `Display`.
+#[doc(hidden)]
+pub struct TagValueWrap<T>(pub T);
+
+#[doc(hidden)]
+pub trait NativeTagValue {
+    fn tag_value(&self) -> TagValue;
+}
+
+impl<T: ToTagValue + ?Sized> NativeTagValue for TagValueWrap<&T> {
+    fn tag_value(&self) -> TagValue {
+        self.0.to_tag_value()
+    }
+}
+
+#[doc(hidden)]
+pub trait DisplayTagValue {
+    fn tag_value(&self) -> TagValue;
+}
+
+impl<T: Display + ?Sized> DisplayTagValue for &TagValueWrap<&T> {
+    fn tag_value(&self) -> TagValue {
+        TagValue::String(Cow::Owned(self.0.to_string()))
+    }
+}
+
+// The same for the arguments recorded by `fields(all)`, which may not be
+// `Display`, `(&&&FieldValueWrap(&arg)).field_value()` falls back to `Debug`.
+#[doc(hidden)]
+pub struct FieldValueWrap<T>(pub T);
+
+#[doc(hidden)]
+pub trait NativeFieldValue {
+    fn field_value(&self) -> TagValue;
+}
+
+impl<T: ToTagValue + ?Sized> NativeFieldValue for &&FieldValueWrap<&T> {
+    fn field_value(&self) -> TagValue {
+        self.0.to_tag_value()
+    }
+}
+
+#[doc(hidden)]
+pub trait DisplayFieldValue {
+    fn field_value(&self) -> TagValue;
+}
+
+impl<T: Display + ?Sized> DisplayFieldValue for &FieldValueWrap<&T> {
+    fn field_value(&self) -> TagValue {
+        TagValue::String(Cow::Owned(self.0.to_string()))
+    }
+}
+
+#[doc(hidden)]
+pub trait DebugFieldValue {
+    fn field_value(&self) -> TagValue;
+}
+
+impl<T: Debug + ?Sized> DebugFieldValue for FieldValueWrap<&T> {
+    fn field_value(&self) -> TagValue {
+        TagValue::String(Cow::Owned(format!("{:?}", self.0)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rustracing::tag::TagValue;
+
+    use super::{
+        truncate_tag_value, DebugFieldValue, DisplayFieldValue, DisplayTagValue, FieldValueWrap,
+        NativeFieldValue, NativeTagValue, TagValueWrap,
+    };
+
+    struct Height(u64);
+
+    #[derive(Debug)]
+    struct Hash([u8; 2]);
+
+    impl std::fmt::Display for Height {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "#{}", self.0)
+        }
+    }
+
+    #[test]
+    fn test_tag_value() {
+        assert_eq!((&TagValueWrap(&10u64)).tag_value(), TagValue::Integer(10));
+        assert_eq!((&TagValueWrap(&-1i32)).tag_value(), TagValue::Integer(-1));
+        assert_eq!((&TagValueWrap(&true)).tag_value(), TagValue::Boolean(true));
+        assert_eq!((&TagValueWrap(&0.5f64)).tag_value(), TagValue::Float(0.5));
+        assert_eq!(
+            (&TagValueWrap(&u64::max_value())).tag_value(),
+            TagValue::String(u64::max_value().to_string().into())
+        );
+        assert_eq!(
+            (&TagValueWrap(&Height(3))).tag_value(),
+            TagValue::String("#3".into())
+        );
+    }
+
+    #[test]
+    fn test_field_value() {
+        assert_eq!(
+            (&&&FieldValueWrap(&10u64)).field_value(),
+            TagValue::Integer(10)
+        );
+        assert_eq!(
+            (&&&FieldValueWrap(&Height(3))).field_value(),
+            TagValue::String("#3".into())
+        );
+        assert_eq!(
+            (&&&FieldValueWrap(&Hash([1, 2]))).field_value(),
+            TagValue::String("Hash([1, 2])".into())
+        );
+    }
+
+    #[test]
+    fn test_truncate_tag_value() {
+        assert_eq!(
+            truncate_tag_value(TagValue::String("abcdef".into()), 3),
+            TagValue::String("abc...".into())
+        );
+        assert_eq!(
+            truncate_tag_value(TagValue::String("高度".into()), 4),
+            TagValue::String("高...".into())
+        );
+        assert_eq!(
+            truncate_tag_value(TagValue::Integer(123_456), 3),
+            TagValue::Integer(123_456)
+        );
+    }
+}
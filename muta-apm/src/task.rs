@@ -0,0 +1,165 @@
+use std::borrow::Cow;
+use std::future::Future;
+use std::thread::{self, JoinHandle};
+
+use rustracing::tag::Tag;
+
+use crate::guard::{KeepFlag, SpanGuard};
+use crate::panic::CatchPanic;
+use crate::MUTA_TRACER;
+
+/// Spawn a thread running `f` under a span named `name`, `kind.name` like
+/// the spans of `#[tracing_span]`, which follows from the span of `ctx`. `f`
+/// is given the child context, the span is finished when it returns.
+pub fn spawn<N, F, T>(ctx: &creep::Context, name: N, f: F) -> JoinHandle<T>
+where
+    N: Into<Cow<'static, str>>,
+    F: FnOnce(creep::Context) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let span = start_span(ctx, name.into(), true);
+    let child_ctx = span.child_or_clone(ctx);
+
+    thread::spawn(move || {
+        let _entered = span.enter();
+        f(child_ctx)
+    })
+}
+
+/// Instrument the future built by `f` from the child context with a span
+/// following from the span of `ctx`, for work spawned on any runtime, e.g.
+/// `tokio::spawn(follows_from(&ctx, "consensus.broadcast", |ctx| ..))`.
+pub fn follows_from<N, F, Fut>(
+    ctx: &creep::Context,
+    name: N,
+    f: F,
+) -> impl Future<Output = Fut::Output>
+where
+    N: Into<Cow<'static, str>>,
+    F: FnOnce(creep::Context) -> Fut,
+    Fut: Future,
+{
+    instrument(start_span(ctx, name.into(), true), ctx, f)
+}
+
+/// Like `follows_from` with a child span, for work the caller waits for.
+pub fn child_of<N, F, Fut>(ctx: &creep::Context, name: N, f: F) -> impl Future<Output = Fut::Output>
+where
+    N: Into<Cow<'static, str>>,
+    F: FnOnce(creep::Context) -> Fut,
+    Fut: Future,
+{
+    instrument(start_span(ctx, name.into(), false), ctx, f)
+}
+
+fn instrument<F, Fut>(
+    mut span: SpanGuard,
+    ctx: &creep::Context,
+    f: F,
+) -> impl Future<Output = Fut::Output>
+where
+    F: FnOnce(creep::Context) -> Fut,
+    Fut: Future,
+{
+    let fut = {
+        let _entered = span.enter();
+        f(span.child_or_clone(ctx))
+    };
+
+    async move { CatchPanic::new(&mut span, fut).await }
+}
+
+// A new trace is started if `ctx` holds no span, like `#[tracing_span]`.
+fn start_span(ctx: &creep::Context, name: Cow<'static, str>, follows_from: bool) -> SpanGuard {
+    let kind = name.split('.').next().unwrap_or_default().to_owned();
+    let tags = vec![Tag::new("kind", kind)];
+
+    let span = if follows_from {
+        MUTA_TRACER.follows_from_ctx(name, ctx, tags)
+    } else {
+        MUTA_TRACER.child_of_ctx(name, ctx, tags)
+    };
+    let parent_keep = ctx.get::<KeepFlag>("parent_span_keep").cloned();
+
+    SpanGuard::new(span, parent_keep, None)
+}
+
+#[cfg(test)]
+mod test {
+    use rustracing_jaeger::span::{FinishedSpan, Span};
+
+    use super::{child_of, follows_from, spawn};
+    use crate::{recv_test_span, register_test_tracer, span_id, MutaTracer, MUTA_TRACER};
+
+    fn parent_ctx(opt_name: &'static str) -> (Span, creep::Context) {
+        register_test_tracer();
+        let parent = MUTA_TRACER.span(opt_name, Vec::new()).unwrap();
+        let span_state = parent.context().unwrap().state().clone();
+        let ctx = MutaTracer::inject_span_state(creep::Context::new(), span_state);
+
+        (parent, ctx)
+    }
+
+    // Whether the span follows from its parent, and the id of the parent.
+    fn reference(span: &FinishedSpan) -> (bool, Option<u64>) {
+        let reference = &span.references()[0];
+        (
+            reference.is_follows_from(),
+            Some(reference.span().span_id()),
+        )
+    }
+
+    #[test]
+    fn test_spawn() {
+        let ctx = creep::Context::new().with_value("height", 7u64);
+        let handle = spawn(&ctx, "consensus.sync", |ctx| {
+            ctx.get::<u64>("height").cloned()
+        });
+
+        assert_eq!(handle.join().unwrap(), Some(7));
+    }
+
+    #[test]
+    fn test_spawn_span() {
+        let (_parent, ctx) = parent_ctx("task.spawn_parent");
+        let child_id = spawn(&ctx, "task.spawn", |child_ctx| span_id(&child_ctx))
+            .join()
+            .unwrap();
+        assert!(child_id.is_some());
+        assert_ne!(child_id, span_id(&ctx));
+
+        let span = recv_test_span("task.spawn");
+        assert_eq!(span.context().state().span_id(), child_id.unwrap());
+        assert_eq!(reference(&span), (true, span_id(&ctx)));
+    }
+
+    #[tokio::test]
+    async fn test_follows_from() {
+        let (_parent, ctx) = parent_ctx("task.follows_from_parent");
+        let child_id = follows_from(&ctx, "task.follows_from", |child_ctx| async move {
+            span_id(&child_ctx)
+        })
+        .await;
+        assert!(child_id.is_some());
+        assert_ne!(child_id, span_id(&ctx));
+
+        let span = recv_test_span("task.follows_from");
+        assert_eq!(span.context().state().span_id(), child_id.unwrap());
+        assert_eq!(reference(&span), (true, span_id(&ctx)));
+    }
+
+    #[tokio::test]
+    async fn test_child_of() {
+        let (_parent, ctx) = parent_ctx("task.child_of_parent");
+        let child_id = child_of(&ctx, "task.child_of", |child_ctx| async move {
+            span_id(&child_ctx)
+        })
+        .await;
+        assert!(child_id.is_some());
+        assert_ne!(child_id, span_id(&ctx));
+
+        let span = recv_test_span("task.child_of");
+        assert_eq!(span.context().state().span_id(), child_id.unwrap());
+        assert_eq!(reference(&span), (false, span_id(&ctx)));
+    }
+}
@@ -0,0 +1,237 @@
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Once;
+
+use async_trait::async_trait;
+use creep::Context;
+use muta_apm::derive::{apm_span, tracing_impl, tracing_span};
+use muta_apm::{span_id, Correlation, MutaTracer, TracerConfig, MUTA_TRACER};
+
+static REGISTER: Once = Once::new();
+
+// A context holding a root span, the spans are sent to a local port nobody
+// listens on.
+fn root_ctx() -> Context {
+    REGISTER.call_once(|| {
+        let udp_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 6831);
+        muta_apm::register_with(TracerConfig::new("muta-apm-test", udp_addr));
+    });
+
+    let root = MUTA_TRACER.span("test.root", Vec::new()).unwrap();
+    MutaTracer::inject_span_state(Context::new(), root.context().unwrap().state().clone())
+}
+
+fn current_span_id() -> Option<u64> {
+    Correlation::current().map(|correlation| correlation.span_id)
+}
+
+#[async_trait]
+trait Storage {
+    async fn get(&self, ctx: Context, height: u64) -> Option<u64>;
+
+    async fn get_ref(&self, ctx: &Context, height: u64) -> Option<u64>;
+
+    async fn put(&self, ctx: &mut Context, height: u64);
+
+    async fn len(&self, ctx: Context) -> Option<u64>;
+}
+
+struct MemStorage;
+
+#[tracing_impl(kind = "storage", skip(len))]
+#[async_trait]
+impl Storage for MemStorage {
+    async fn get(&self, ctx: Context, height: u64) -> Option<u64> {
+        assert_eq!(span_id(&ctx), current_span_id());
+        span_id(&ctx)
+    }
+
+    async fn get_ref(&self, ctx: &Context, height: u64) -> Option<u64> {
+        assert_eq!(span_id(ctx), current_span_id());
+        span_id(ctx)
+    }
+
+    async fn put(&self, ctx: &mut Context, height: u64) {
+        assert_eq!(span_id(ctx), current_span_id());
+        *ctx = ctx.with_value("height", height);
+    }
+
+    async fn len(&self, ctx: Context) -> Option<u64> {
+        assert_eq!(current_span_id(), None);
+        span_id(&ctx)
+    }
+}
+
+#[tracing_span(kind = "test")]
+fn get_fut<'a>(ctx: &'a Context) -> impl Future<Output = Option<u64>> + 'a {
+    async move {
+        assert_eq!(span_id(ctx), current_span_id());
+        span_id(ctx)
+    }
+}
+
+#[tracing_span(kind = "test")]
+fn put_sync(ctx: &mut Context, height: u64) -> Result<(), String> {
+    *ctx = ctx.with_value("height", height);
+    if height == 0 {
+        Err("zero height".to_owned())?;
+    }
+    Ok(())
+}
+
+// Arguments named like the bindings of the generated code.
+#[tracing_span(kind = "test")]
+fn ref_names(ctx: &Context, child_ctx: u64, parent_keys: u64) -> u64 {
+    child_ctx + parent_keys
+}
+
+#[tracing_span(kind = "test")]
+fn ref_mut_names(ctx: &mut Context, child_ctx: u64, parent_keys: u64) -> u64 {
+    child_ctx + parent_keys
+}
+
+struct Heights(Vec<u64>);
+
+impl Heights {
+    #[tracing_span(kind = "test")]
+    fn get(&self, ctx: Context, index: usize) -> Result<&u64, String> {
+        let height = self.0.get(index).ok_or("no height")?;
+        Ok(height)
+    }
+
+    #[tracing_span(kind = "test")]
+    fn get_mut(&mut self, ctx: Context, index: usize) -> Result<&mut u64, String> {
+        self.0.get_mut(index).ok_or_else(|| "no height".to_owned())
+    }
+}
+
+struct Request {
+    ctx: Context,
+}
+
+impl Request {
+    #[tracing_span(kind = "test", ctx = "self.ctx")]
+    fn handle(&mut self) -> Option<u64> {
+        assert_eq!(span_id(&self.ctx), current_span_id());
+        span_id(&self.ctx)
+    }
+}
+
+#[tokio::test]
+async fn test_owned_context() {
+    let ctx = root_ctx();
+    let child_id = MemStorage.get(ctx.clone(), 1).await;
+
+    assert!(child_id.is_some());
+    assert_ne!(child_id, span_id(&ctx));
+}
+
+#[tokio::test]
+async fn test_ref_context() {
+    let ctx = root_ctx();
+
+    let child_id = MemStorage.get_ref(&ctx, 1).await;
+    assert!(child_id.is_some());
+    assert_ne!(child_id, span_id(&ctx));
+
+    let child_id = get_fut(&ctx).await;
+    assert!(child_id.is_some());
+    assert_ne!(child_id, span_id(&ctx));
+}
+
+#[tokio::test]
+async fn test_ref_mut_context() {
+    let mut ctx = root_ctx();
+    let parent_id = span_id(&ctx);
+
+    MemStorage.put(&mut ctx, 2).await;
+    assert_eq!(ctx.get::<u64>("height"), Some(&2));
+    assert_eq!(span_id(&ctx), parent_id);
+
+    put_sync(&mut ctx, 3).unwrap();
+    assert_eq!(ctx.get::<u64>("height"), Some(&3));
+    assert_eq!(span_id(&ctx), parent_id);
+
+    // The parent span is restored after an early return too.
+    assert!(put_sync(&mut ctx, 0).is_err());
+    assert_eq!(span_id(&ctx), parent_id);
+
+    let mut ctx = Context::new();
+    put_sync(&mut ctx, 4).unwrap();
+    assert_eq!(span_id(&ctx), None);
+}
+
+#[test]
+fn test_generated_names() {
+    let mut ctx = root_ctx();
+
+    assert_eq!(ref_names(&ctx, 1, 2), 3);
+    assert_eq!(ref_mut_names(&mut ctx, 1, 2), 3);
+}
+
+#[test]
+fn test_borrowed_return() {
+    let mut heights = Heights(vec![1]);
+
+    assert_eq!(heights.get(root_ctx(), 0), Ok(&1));
+    assert!(heights.get(root_ctx(), 1).is_err());
+    *heights.get_mut(root_ctx(), 0).unwrap() = 2;
+    assert_eq!(heights.0, vec![2]);
+}
+
+#[tokio::test]
+async fn test_skipped_method() {
+    let ctx = root_ctx();
+
+    assert_eq!(MemStorage.len(ctx.clone()).await, span_id(&ctx));
+}
+
+#[test]
+fn test_place_context() {
+    let ctx = root_ctx();
+    let parent_id = span_id(&ctx);
+    let mut request = Request { ctx };
+
+    let child_id = request.handle();
+    assert!(child_id.is_some());
+    assert_ne!(child_id, parent_id);
+    assert_eq!(span_id(&request.ctx), parent_id);
+
+    let child_id = apm_span!(request.ctx, "test.block", { span_id(&request.ctx) });
+    assert!(child_id.is_some());
+    assert_ne!(child_id, parent_id);
+    assert_eq!(span_id(&request.ctx), parent_id);
+}
+
+#[test]
+fn test_span_block_early_return() {
+    let ctx = root_ctx();
+    let parent_id = span_id(&ctx);
+    let mut request = Request { ctx };
+
+    let ret = apm_span!(request.ctx, "test.block", err, {
+        assert_ne!(span_id(&request.ctx), parent_id);
+        Err("early return".to_owned())?;
+        Ok::<_, String>(span_id(&request.ctx))
+    });
+    assert_eq!(ret, Err("early return".to_owned()));
+    assert_eq!(span_id(&request.ctx), parent_id);
+
+    let ctx = request.ctx;
+    let ret = apm_span!(ctx, "test.block", err, {
+        "2".parse::<u64>()?;
+        Ok::<_, std::num::ParseIntError>(span_id(&ctx))
+    });
+    assert_ne!(ret.unwrap(), parent_id);
+}
+
+#[test]
+fn test_span_block_ref_context() {
+    let ctx = root_ctx();
+    let ctx_ref = &ctx;
+
+    // Still a `&Context` inside the block.
+    let child_id = apm_span!(ctx_ref, "test.block", { span_id(ctx_ref) });
+    assert!(child_id.is_some());
+    assert_ne!(child_id, span_id(&ctx));
+}
@@ -0,0 +1,41 @@
+// Run with `--features disable-tracing`, `apm_span!` blocks behave the same
+// without tracing.
+#![cfg(feature = "disable-tracing")]
+
+use std::num::ParseIntError;
+
+use creep::Context;
+use muta_apm::derive::apm_span;
+
+fn height(ctx: &Context) -> Option<u64> {
+    ctx.get::<u64>("height").cloned()
+}
+
+#[test]
+fn test_span_block() {
+    let ctx = Context::new().with_value("height", 1u64);
+    let ctx_ref = &ctx;
+
+    let ret = apm_span!(ctx_ref, "test.block", {
+        assert_eq!(height(ctx_ref), Some(1));
+        "one".parse::<u64>()?;
+        Ok::<_, ParseIntError>(2)
+    });
+    assert!(ret.is_err());
+
+    let ret = apm_span!(ctx, "test.block", {
+        let ctx: Context = ctx;
+        height(&ctx)
+    });
+    assert_eq!(ret, Some(1));
+}
+
+#[tokio::test]
+async fn test_span_async_block() {
+    let ctx = Context::new().with_value("height", 1u64);
+
+    // The block owns a clone of the context.
+    let fut = apm_span!(ctx, "test.async", async move { height(&ctx) });
+    assert_eq!(height(&ctx), Some(1));
+    assert_eq!(fut.await, Some(1));
+}
@@ -0,0 +1,40 @@
+use std::net::UdpSocket;
+use std::time::Duration;
+
+use creep::Context;
+use muta_apm::derive::tracing_span;
+use muta_apm::TracerConfig;
+
+#[tracing_span(kind = "test", slow_threshold_ms = 60000)]
+fn load(ctx: Context, height: u64) -> Result<u64, String> {
+    if height == 0 {
+        Err("zero height".to_owned())?;
+    }
+    Ok(height)
+}
+
+fn contains(packet: &[u8], s: &str) -> bool {
+    packet.windows(s.len()).any(|window| window == s.as_bytes())
+}
+
+// Spans are reported one by one to a local socket, the compact thrift
+// encoding keeps their strings as is.
+#[test]
+fn test_early_return_reported() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let config = TracerConfig::new("muta-apm-test", socket.local_addr().unwrap()).batch_size(1);
+    muta_apm::register_with(config);
+
+    // Both are faster than the threshold, the errored one is still reported.
+    assert_eq!(load(Context::new(), 1), Ok(1));
+    assert!(load(Context::new(), 0).is_err());
+
+    let mut buf = vec![0; 65_536];
+    let len = socket.recv(&mut buf).unwrap();
+    let packet = &buf[..len];
+    assert!(contains(packet, "test.load"));
+    assert!(contains(packet, "zero height"));
+}